@@ -0,0 +1,303 @@
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+    process,
+};
+
+use chat::{
+    client::{Client, Error, ServerMessage},
+    FileOffer, MessageType, Moderation,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use structopt::StructOpt;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+/// Exit codes, 1 is any other error.
+const EXIT_CONNECT: i32 = 2;
+const EXIT_BAD_USERNAME: i32 = 3;
+const EXIT_USERNAME_EXISTS: i32 = 4;
+const EXIT_BANNED: i32 = 5;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "chat-cli",
+    about = "Chat client for scripts.",
+    after_help = "Exits with 2 if the server can't be reached, 3 if the username is invalid, \
+                  4 if it is taken and 5 if it is banned."
+)]
+struct Opt {
+    /// Set address of the server
+    #[structopt(short, long, default_value = "127.0.0.1:8080")]
+    address: String,
+
+    #[structopt(short, long)]
+    username: String,
+
+    /// Where received files and the key for direct messages are kept
+    #[structopt(short, long, default_value = ".")]
+    save_directory: PathBuf,
+
+    #[structopt(subcommand)]
+    command: Cmd,
+}
+
+#[derive(Debug, StructOpt)]
+enum Cmd {
+    /// Send text given as arguments, or every line of stdin without them
+    Send { text: Vec<String> },
+    /// Upload a file and wait until it is offered to everyone
+    SendFile {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Print incoming messages until interrupted or disconnected
+    Listen {
+        /// Print messages as JSON lines
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Print the last messages from the server's history
+    Tail {
+        #[structopt(short = "n", long, default_value = "10")]
+        lines: usize,
+        /// Print messages as JSON lines
+        #[structopt(long)]
+        json: bool,
+    },
+}
+
+/// A message as `--json` prints it.
+#[derive(Serialize)]
+struct Line<'a> {
+    r#type: MessageType,
+    from: &'a str,
+    timestamp: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    action: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filename: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offer: Option<&'a FileOffer>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    saved: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    moderation: Option<&'a Moderation>,
+}
+
+#[tokio::main]
+async fn main() {
+    let Opt {
+        address,
+        username,
+        save_directory,
+        command,
+    } = Opt::from_args();
+    let client = match Client::new(username.clone(), address.as_str(), save_directory).await {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(match e {
+                Error::Io(_) => EXIT_CONNECT,
+                Error::BadUsername => EXIT_BAD_USERNAME,
+                Error::UsernameExists => EXIT_USERNAME_EXISTS,
+                Error::Banned => EXIT_BANNED,
+                _ => 1,
+            });
+        }
+    };
+
+    let result = match command {
+        Cmd::Send { text } => send(&client, text).await,
+        Cmd::SendFile { path } => send_file(&client, &username, path).await,
+        Cmd::Listen { json } => listen(&client, json).await,
+        Cmd::Tail { lines, json } => tail(&client, &username, lines, json).await,
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+async fn send(client: &Client, text: Vec<String>) -> Result<(), Error> {
+    if text.is_empty() {
+        // read asynchronously, the client's tasks share the runtime with this one
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Some(line) = lines.next_line().await? {
+            if !line.is_empty() {
+                client.send_text(line).await;
+            }
+        }
+    } else {
+        client.send_text(text.join(" ")).await;
+    }
+    sync(client).await
+}
+
+/// Waits until the server handled everything sent so far, it closes the connection after that.
+async fn sync(client: &Client) -> Result<(), Error> {
+    client.close().await;
+    while let Some(msg) = client.next_message().await {
+        if matches!(
+            msg.desc.r#type,
+            MessageType::Forbidden | MessageType::QuotaExceeded
+        ) {
+            return Err(refused(&msg));
+        }
+    }
+    Ok(())
+}
+
+async fn send_file(client: &Client, username: &str, path: PathBuf) -> Result<(), Error> {
+    client.send_file(path).await?;
+    loop {
+        let msg = next(client).await?;
+        match msg.desc.r#type {
+            MessageType::FileOffer if msg.from == username => return Ok(()),
+            MessageType::Forbidden | MessageType::QuotaExceeded => return Err(refused(&msg)),
+            MessageType::ChecksumMismatch if msg.from == username => {
+                return Err(Error::ChecksumMismatch)
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn listen(client: &Client, json: bool) -> Result<(), Error> {
+    while let Some(msg) = client.next_message().await {
+        print(&msg, json)?;
+    }
+    Ok(())
+}
+
+/// History comes right after logging in, before the server announces our own login.
+async fn tail(client: &Client, username: &str, lines: usize, json: bool) -> Result<(), Error> {
+    let mut history = Vec::new();
+    loop {
+        let msg = next(client).await?;
+        match msg.desc.r#type {
+            MessageType::Login if msg.from == username => break,
+            MessageType::Utf8 => history.push(msg),
+            _ => {}
+        }
+    }
+    let skip = history.len().saturating_sub(lines);
+    for msg in &history[skip..] {
+        print(msg, json)?;
+    }
+    Ok(())
+}
+
+async fn next(client: &Client) -> Result<ServerMessage, Error> {
+    client
+        .next_message()
+        .await
+        .ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionAborted).into())
+}
+
+/// The server's reason if it gave one.
+fn refused(msg: &ServerMessage) -> Error {
+    let reason = if !msg.content.is_empty() {
+        String::from_utf8_lossy(&msg.content).into_owned()
+    } else if msg.desc.r#type == MessageType::QuotaExceeded {
+        "quota exceeded".to_string()
+    } else {
+        "forbidden".to_string()
+    };
+    io::Error::new(io::ErrorKind::PermissionDenied, reason).into()
+}
+
+fn print(msg: &ServerMessage, json: bool) -> io::Result<()> {
+    let line = if json {
+        json_line(msg)
+    } else {
+        match plain_line(msg) {
+            Some(line) => line,
+            None => return Ok(()),
+        }
+    };
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "{}", line)?;
+    // listen is usually piped somewhere that wants each message as it comes
+    stdout.flush()
+}
+
+fn json_line(msg: &ServerMessage) -> String {
+    let text = matches!(
+        msg.desc.r#type,
+        MessageType::Utf8
+            | MessageType::Direct
+            | MessageType::ServerNotice
+            | MessageType::Nick
+            | MessageType::Forbidden
+            | MessageType::QuotaExceeded
+    )
+    .then(|| String::from_utf8_lossy(&msg.content).into_owned());
+    serde_json::to_string(&Line {
+        r#type: msg.desc.r#type,
+        from: &msg.from,
+        timestamp: msg.timestamp,
+        text,
+        action: msg.action,
+        filename: msg.filename.as_deref(),
+        offer: msg.offer.as_ref(),
+        saved: msg.saved.as_ref().map(|p| p.display().to_string()),
+        moderation: msg.moderation.as_ref(),
+    })
+    .unwrap()
+}
+
+fn plain_line(msg: &ServerMessage) -> Option<String> {
+    let from = &msg.from;
+    let text = String::from_utf8_lossy(&msg.content);
+    let saved = match &msg.saved {
+        Some(path) => format!(" -> {}", path.display()),
+        None => String::new(),
+    };
+    let line = match msg.desc.r#type {
+        MessageType::Utf8 if msg.action => format!("* {} {}", from, text),
+        MessageType::Utf8 => format!("<{}> {}", from, text),
+        MessageType::Direct => format!("<{} (direct)> {}", from, text),
+        MessageType::ServerNotice => format!("-- {}", text),
+        MessageType::Login => format!("{} joined", from),
+        MessageType::Logout => format!("{} left", from),
+        MessageType::Nick => format!("{} is now known as {}", from, text),
+        MessageType::Image => format!("{} sent an image", from),
+        MessageType::Voice => format!("{} sent a voice message{}", from, saved),
+        MessageType::FileOffer => format!(
+            "{} offers {} ({} bytes)",
+            from,
+            msg.filename.as_deref().unwrap_or_default(),
+            msg.offer.as_ref().map_or(0, |o| o.size)
+        ),
+        MessageType::File => format!(
+            "received {}{}",
+            msg.filename.as_deref().unwrap_or_default(),
+            saved
+        ),
+        MessageType::Kick
+        | MessageType::Ban
+        | MessageType::Unban
+        | MessageType::Mute
+        | MessageType::Unmute => {
+            let moderation = msg.moderation.as_ref()?;
+            let action = match msg.desc.r#type {
+                MessageType::Kick => "kicked",
+                MessageType::Ban => "banned",
+                MessageType::Unban => "unbanned",
+                MessageType::Mute => "muted",
+                _ => "unmuted",
+            };
+            let mut line = format!("{} was {} by {}", moderation.user, action, from);
+            if let Some(reason) = &moderation.reason {
+                line.push_str(&format!(": {}", reason));
+            }
+            line
+        }
+        _ => return None,
+    };
+    let time = msg.timestamp.naive_local().time().format("%H:%M:%S");
+    Some(format!("[{}] {}", time, line))
+}
@@ -0,0 +1,86 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+};
+
+use chat::server::{ControlRequest, ControlResponse};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "chatctl", about = "Controls a running chat server.")]
+struct Opt {
+    /// Control socket of the server
+    #[structopt(short, long, parse(from_os_str), default_value = "chat.sock")]
+    socket: PathBuf,
+
+    #[structopt(subcommand)]
+    command: Cmd,
+}
+
+#[derive(Debug, StructOpt)]
+enum Cmd {
+    /// List connected users
+    Users,
+    /// Disconnect a user
+    Kick {
+        user: String,
+        #[structopt(short, long)]
+        reason: Option<String>,
+    },
+    /// Send a notice to everyone
+    Announce { text: Vec<String> },
+    /// Show server statistics
+    Stats,
+    /// Reload the ban list
+    Reload,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let Opt { socket, command } = Opt::from_args();
+    let request = match command {
+        Cmd::Users => ControlRequest::Users,
+        Cmd::Kick { user, reason } => ControlRequest::Kick { user, reason },
+        Cmd::Announce { text } => ControlRequest::Announce {
+            text: text.join(" "),
+        },
+        Cmd::Stats => ControlRequest::Stats,
+        Cmd::Reload => ControlRequest::Reload,
+    };
+
+    let mut stream = UnixStream::connect(&socket)?;
+    let mut line = serde_json::to_string(&request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+
+    match serde_json::from_str(&line)? {
+        ControlResponse::Ok => println!("ok"),
+        ControlResponse::Users { users } => {
+            for user in users {
+                let mut flags = Vec::new();
+                if user.admin {
+                    flags.push("admin");
+                }
+                if user.muted {
+                    flags.push("muted");
+                }
+                println!("{}\t{}\t{}", user.name, user.addr, flags.join(","));
+            }
+        }
+        ControlResponse::Stats(stats) => {
+            println!("users:    {}", stats.users);
+            println!("offers:   {}", stats.offers);
+            println!("relayed:  {}", stats.relayed);
+            println!("uptime:   {}s", stats.uptime_secs);
+            println!("spool:    {} bytes", stats.storage.total);
+            println!("disk:     {} bytes free", stats.storage.available);
+        }
+        ControlResponse::Error { message } => {
+            eprintln!("error: {}", message);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
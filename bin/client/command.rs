@@ -0,0 +1,105 @@
+use std::path::PathBuf;
+
+use regex::Regex;
+
+/// What the user asked for in the input box.
+pub enum Command {
+    Text(String),
+    File(PathBuf),
+    Image(PathBuf),
+    Voice(PathBuf),
+    /// Save a received image, the latest one if no index is given.
+    Save(Option<usize>),
+    /// Download an offered file, the latest one if no index is given.
+    Accept(Option<usize>),
+    /// Ask the server how much storage uploads take.
+    Usage,
+    /// Send an end-to-end encrypted message to a single user.
+    Direct {
+        to: String,
+        text: String,
+    },
+    Kick {
+        user: String,
+        reason: Option<String>,
+    },
+    Ban {
+        user: String,
+        reason: Option<String>,
+    },
+    Unban(String),
+    /// Mute a user for the given number of minutes, or until unmuted.
+    Mute {
+        user: String,
+        minutes: Option<u64>,
+    },
+    Unmute(String),
+}
+
+impl Command {
+    pub fn parse(input: &str) -> Self {
+        lazy_static::lazy_static! {
+            static ref RE: Regex = Regex::new(r"^/(?P<cmd>file|image|voice|save|accept|usage|dm|kick|ban|unban|mute|unmute)(?: +(?P<arg>.+?))? *$").unwrap();
+        }
+        let c = match RE.captures(input) {
+            Some(c) => c,
+            None => return Command::Text(input.to_string()),
+        };
+        let arg = c.name("arg").map(|m| m.as_str());
+        match (&c["cmd"], arg) {
+            ("file", Some(path)) => Command::File(PathBuf::from(path)),
+            ("image", Some(path)) => Command::Image(PathBuf::from(path)),
+            ("voice", Some(path)) => Command::Voice(PathBuf::from(path)),
+            ("save", None) => Command::Save(None),
+            ("save", Some(n)) => match n.parse() {
+                Ok(n) => Command::Save(Some(n)),
+                Err(_) => Command::Text(input.to_string()),
+            },
+            ("accept", None) => Command::Accept(None),
+            ("accept", Some(n)) => match n.parse() {
+                Ok(n) => Command::Accept(Some(n)),
+                Err(_) => Command::Text(input.to_string()),
+            },
+            ("usage", None) => Command::Usage,
+            ("dm", Some(arg)) => match arg.split_once(' ') {
+                Some((to, text)) => Command::Direct {
+                    to: to.to_string(),
+                    text: text.trim_start().to_string(),
+                },
+                None => Command::Text(input.to_string()),
+            },
+            ("kick", Some(arg)) => {
+                let (user, reason) = split_reason(arg);
+                Command::Kick { user, reason }
+            }
+            ("ban", Some(arg)) => {
+                let (user, reason) = split_reason(arg);
+                Command::Ban { user, reason }
+            }
+            ("unban", Some(user)) => Command::Unban(user.to_string()),
+            ("mute", Some(arg)) => match split_reason(arg) {
+                (user, None) => Command::Mute {
+                    user,
+                    minutes: None,
+                },
+                (user, Some(minutes)) => match minutes.parse() {
+                    Ok(minutes) => Command::Mute {
+                        user,
+                        minutes: Some(minutes),
+                    },
+                    Err(_) => Command::Text(input.to_string()),
+                },
+            },
+            ("unmute", Some(user)) => Command::Unmute(user.to_string()),
+            _ => Command::Text(input.to_string()),
+        }
+    }
+}
+
+/// Splits `user rest of the line` into the username and the optional rest.
+fn split_reason(arg: &str) -> (String, Option<String>) {
+    match arg.split_once(' ') {
+        Some((user, reason)) => (user.to_string(), Some(reason.trim().to_string())),
+        None => (arg.to_string(), None),
+    }
+}
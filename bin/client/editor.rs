@@ -0,0 +1,243 @@
+/// How many sent messages Up and Down go back through.
+const HISTORY_LEN: usize = 100;
+
+/// The text being typed, possibly over several lines, with a cursor and the messages sent before.
+#[derive(Default)]
+pub struct Editor {
+    text: String,
+    /// Byte offset into `text`, always on a char boundary.
+    cursor: usize,
+    history: Vec<String>,
+    /// The entry of `history` shown while going through it.
+    browsing: Option<usize>,
+    /// What was typed before going through the history.
+    draft: String,
+}
+
+impl Editor {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// How many lines the text takes.
+    pub fn lines(&self) -> usize {
+        self.text.split('\n').count()
+    }
+
+    /// Column and line of the cursor, in chars.
+    pub fn cursor(&self) -> (usize, usize) {
+        let before = &self.text[..self.cursor];
+        let line = before.matches('\n').count();
+        let column = before[self.line_start()..].chars().count();
+        (column, line)
+    }
+
+    /// Takes the text to be sent and remembers it in the history.
+    pub fn take(&mut self) -> String {
+        let text = std::mem::take(&mut self.text);
+        self.cursor = 0;
+        self.browsing = None;
+        self.draft.clear();
+        if !text.is_empty() && self.history.last() != Some(&text) {
+            self.history.push(text.clone());
+            if self.history.len() > HISTORY_LEN {
+                self.history.remove(0);
+            }
+        }
+        text
+    }
+
+    pub fn insert(&mut self, ch: char) {
+        self.text.insert(self.cursor, ch);
+        self.cursor += ch.len_utf8();
+    }
+
+    pub fn backspace(&mut self) {
+        if let Some(ch) = self.text[..self.cursor].chars().next_back() {
+            self.cursor -= ch.len_utf8();
+            self.text.remove(self.cursor);
+        }
+    }
+
+    pub fn delete(&mut self) {
+        if self.cursor < self.text.len() {
+            self.text.remove(self.cursor);
+        }
+    }
+
+    /// Deletes the word before the cursor along with the spaces after it. At the start of a
+    /// line it deletes the line break instead, joining the line with the one above.
+    pub fn delete_word(&mut self) {
+        let before = self.text[..self.cursor].trim_end_matches([' ', '\t']);
+        let start = match before.strip_suffix('\n') {
+            Some(above) => above.len(),
+            None => before
+                .rfind(char::is_whitespace)
+                .map_or(0, |i| i + before[i..].chars().next().unwrap().len_utf8()),
+        };
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    pub fn left(&mut self) {
+        if let Some(ch) = self.text[..self.cursor].chars().next_back() {
+            self.cursor -= ch.len_utf8();
+        }
+    }
+
+    pub fn right(&mut self) {
+        if let Some(ch) = self.text[self.cursor..].chars().next() {
+            self.cursor += ch.len_utf8();
+        }
+    }
+
+    /// Moves to the start of the current line.
+    pub fn home(&mut self) {
+        self.cursor = self.line_start();
+    }
+
+    /// Moves to the end of the current line.
+    pub fn end(&mut self) {
+        self.cursor = self.line_end(self.cursor);
+    }
+
+    /// Moves a line up, or to the previous message sent from the first line.
+    pub fn up(&mut self) {
+        let start = self.line_start();
+        if start == 0 {
+            let index = match self.browsing {
+                Some(0) => return,
+                Some(index) => index - 1,
+                None if self.history.is_empty() => return,
+                None => {
+                    self.draft = std::mem::take(&mut self.text);
+                    self.history.len() - 1
+                }
+            };
+            self.show(index);
+            return;
+        }
+        let column = self.text[start..self.cursor].chars().count();
+        let prev = self.text[..start - 1].rfind('\n').map_or(0, |i| i + 1);
+        self.cursor = self.column_at(prev, column);
+    }
+
+    /// Moves a line down, or to the next message sent from the last line.
+    pub fn down(&mut self) {
+        let end = self.line_end(self.cursor);
+        if end == self.text.len() {
+            match self.browsing {
+                Some(index) if index + 1 < self.history.len() => self.show(index + 1),
+                Some(_) => {
+                    self.browsing = None;
+                    self.text = std::mem::take(&mut self.draft);
+                    self.cursor = self.text.len();
+                }
+                None => {}
+            }
+            return;
+        }
+        let column = self.text[self.line_start()..self.cursor].chars().count();
+        self.cursor = self.column_at(end + 1, column);
+    }
+
+    fn show(&mut self, index: usize) {
+        self.browsing = Some(index);
+        self.text = self.history[index].clone();
+        self.cursor = self.text.len();
+    }
+
+    fn line_start(&self) -> usize {
+        self.text[..self.cursor].rfind('\n').map_or(0, |i| i + 1)
+    }
+
+    fn line_end(&self, from: usize) -> usize {
+        self.text[from..]
+            .find('\n')
+            .map_or(self.text.len(), |i| from + i)
+    }
+
+    /// The offset of `column` on the line starting at `start`, or of its end if it's shorter.
+    fn column_at(&self, start: usize, column: usize) -> usize {
+        let end = self.line_end(start);
+        self.text[start..end]
+            .char_indices()
+            .nth(column)
+            .map_or(end, |(i, _)| start + i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(text: &str) -> Editor {
+        let mut editor = Editor::default();
+        text.chars().for_each(|ch| editor.insert(ch));
+        editor
+    }
+
+    #[test]
+    fn delete_word_stops_at_spaces() {
+        let mut editor = typed("привет мир  ");
+        editor.delete_word();
+        assert_eq!(editor.text(), "привет ");
+        editor.delete_word();
+        assert_eq!(editor.text(), "");
+    }
+
+    #[test]
+    fn delete_word_joins_lines_after_a_line_break() {
+        let mut editor = typed("one\n");
+        editor.delete_word();
+        assert_eq!(editor.text(), "one");
+        assert_eq!(editor.cursor(), (3, 0));
+
+        let mut editor = typed("one\ntwo");
+        editor.home();
+        editor.delete_word();
+        assert_eq!(editor.text(), "onetwo");
+        assert_eq!(editor.cursor(), (3, 0));
+    }
+
+    #[test]
+    fn up_and_down_keep_the_column_in_chars() {
+        let mut editor = typed("ёжик\nab\nпятый");
+        assert_eq!(editor.cursor(), (5, 2));
+        editor.up();
+        // the line is shorter, the cursor goes to its end
+        assert_eq!(editor.cursor(), (2, 1));
+        editor.up();
+        assert_eq!(editor.cursor(), (2, 0));
+        editor.insert('-');
+        assert_eq!(editor.text(), "ёж-ик\nab\nпятый");
+        editor.down();
+        assert_eq!(editor.cursor(), (2, 1));
+        editor.down();
+        assert_eq!(editor.cursor(), (2, 2));
+        editor.insert('+');
+        assert_eq!(editor.text(), "ёж-ик\nab\nпя+тый");
+    }
+
+    #[test]
+    fn up_and_down_go_through_history_from_the_edges() {
+        let mut editor = typed("первое");
+        editor.take();
+        "черновик".chars().for_each(|ch| editor.insert(ch));
+        editor.up();
+        assert_eq!(editor.text(), "первое");
+        editor.up();
+        assert_eq!(editor.text(), "первое");
+        editor.down();
+        assert_eq!(editor.text(), "черновик");
+        assert_eq!(editor.cursor(), (8, 0));
+    }
+
+    #[test]
+    fn column_at_clamps_to_the_line_end() {
+        let editor = typed("日本語\nx");
+        assert_eq!(editor.column_at(0, 1), "日".len());
+        assert_eq!(editor.column_at(0, 10), "日本語".len());
+        assert_eq!(editor.column_at("日本語\n".len(), 5), editor.text().len());
+    }
+}
@@ -2,17 +2,24 @@ use std::io;
 use std::path::PathBuf;
 use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::Duration;
 
-use termion::event::Key;
+use termion::event::{self, Key};
 use termion::input::TermRead;
 
 use chat::client::*;
 
 // #[allow(dead_code)]
 
+/// What the terminal sends around pasted text once bracketed paste is on.
+pub const PASTE_START: &[u8] = b"\x1b[200~";
+pub const PASTE_END: &[u8] = b"\x1b[201~";
+
 pub enum Event {
     Input(Key),
-    Recv(ServerMessage),
+    /// Pasted text starts with `true` and ends with `false`, keys in between are the text.
+    Paste(bool),
+    Recv(Box<ServerMessage>),
     Tick,
 }
 
@@ -31,12 +38,16 @@ impl Events {
             let tx = tx.clone();
             thread::spawn(move || {
                 let stdin = io::stdin();
-                for evt in stdin.keys() {
-                    if let Ok(key) = evt {
-                        if let Err(err) = tx.send(Event::Input(key)) {
-                            eprintln!("{}", err);
-                            return;
-                        }
+                for event in stdin.events().flatten() {
+                    let event = match event {
+                        event::Event::Key(key) => Event::Input(key),
+                        event::Event::Unsupported(seq) if seq == PASTE_START => Event::Paste(true),
+                        event::Event::Unsupported(seq) if seq == PASTE_END => Event::Paste(false),
+                        _ => continue,
+                    };
+                    if let Err(err) = tx.send(event) {
+                        eprintln!("{}", err);
+                        return;
                     }
                 }
             })
@@ -58,7 +69,7 @@ impl Events {
             tokio::spawn(async move {
                 loop {
                     let msg = recv_client.recv().await;
-                    if let Err(err) = tx.send(Event::Recv(msg)) {
+                    if let Err(err) = tx.send(Event::Recv(Box::new(msg))) {
                         eprintln!("{}", err);
                         return;
                     }
@@ -77,7 +88,56 @@ impl Events {
         self.client.send_text(message).await;
     }
 
-    pub async fn send_file(&mut self, file: PathBuf) {
-        self.client.send_file(file).await;
+    pub async fn send_file(&mut self, file: PathBuf) -> Result<(), Error> {
+        self.client.send_file(file).await
+    }
+
+    pub async fn accept_file(&mut self, id: String) {
+        self.client.accept_file(id).await;
+    }
+
+    pub async fn send_direct(&mut self, to: String, text: String) -> Result<(), Error> {
+        self.client.send_direct(to, text).await
+    }
+
+    pub async fn kick(&mut self, user: String, reason: Option<String>) {
+        self.client.kick(user, reason).await;
+    }
+
+    pub async fn ban(&mut self, user: String, reason: Option<String>) {
+        self.client.ban(user, reason).await;
+    }
+
+    pub async fn unban(&mut self, user: String) {
+        self.client.unban(user).await;
+    }
+
+    pub async fn mute(&mut self, user: String, duration: Option<Duration>) {
+        self.client.mute(user, duration, None).await;
+    }
+
+    pub async fn unmute(&mut self, user: String) {
+        self.client.unmute(user).await;
+    }
+
+    pub async fn request_usage(&mut self) {
+        self.client.request_usage().await;
+    }
+
+    pub async fn send_image(&mut self, file: PathBuf) -> Result<(), Error> {
+        self.client.send_image(file).await
+    }
+
+    pub async fn send_voice(&mut self, file: PathBuf) -> Result<(), Error> {
+        self.client.send_voice(file).await
+    }
+
+    pub async fn save(
+        &mut self,
+        from: &str,
+        filename: &str,
+        content: &[u8],
+    ) -> Result<PathBuf, Error> {
+        self.client.save(from, filename, content).await
     }
 }
@@ -1,11 +1,20 @@
+mod command;
+mod editor;
 mod event;
 
 use chat::client::Client;
-use chat::MessageType;
+use chat::markup::{self, Inline};
+use chat::media::Thumbnail;
+use chat::{MessageType, UsageReport, SYSTEM_USER};
+use command::Command;
+use editor::Editor;
 use event::*;
-use regex::Regex;
 use std::path::PathBuf;
-use std::{error::Error, io};
+use std::time::Duration;
+use std::{
+    error::Error,
+    io::{self, Write},
+};
 use std::{net::SocketAddr, str::FromStr};
 use structopt::StructOpt;
 use termion::{event::Key, input::MouseTerminal, raw::IntoRawMode, screen::AlternateScreen};
@@ -13,11 +22,17 @@ use tui::{
     backend::TermionBackend,
     layout::{Alignment, Constraint, Direction, Layout},
     style::{Color, Modifier, Style},
-    text::{Span, Spans},
+    text::{Span, Spans, Text},
     widgets::{Block, Borders, Paragraph, Wrap},
     Terminal,
 };
 
+/// The input pane grows with the text up to this many lines.
+const MAX_INPUT_LINES: usize = 8;
+
+/// How many lines PageUp and PageDown scroll the messages.
+const SCROLL_PAGE: u16 = 10;
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "client", about = "Simple TCP chat room.")]
 struct Opt {
@@ -31,6 +46,22 @@ struct Opt {
 
     #[structopt(short, long, default_value = ".")]
     save_directory: PathBuf,
+
+    /// Save received files into a subdirectory per sender
+    #[structopt(long)]
+    sender_dirs: bool,
+
+    /// Only save received files with these extensions
+    #[structopt(long)]
+    allow_ext: Vec<String>,
+
+    /// Never save received files with these extensions
+    #[structopt(long)]
+    deny_ext: Vec<String>,
+
+    /// Log in as an admin with the server's admin token
+    #[structopt(long)]
+    admin_token: Option<String>,
 }
 
 #[tokio::main]
@@ -38,14 +69,24 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // console_subscriber::init();
     let Opt {
         address,
-        username,
+        mut username,
         save_directory,
+        sender_dirs,
+        allow_ext,
+        deny_ext,
+        admin_token,
     } = Opt::from_args();
     let addr = SocketAddr::from_str(address.as_str()).unwrap();
-    let title_text = format!("{} as {}", address, username);
-    let client = Client::new(username.clone(), addr, save_directory)
-        .await
-        .unwrap();
+    let mut title_text = format!("{} as {}", address, username);
+    let client = match admin_token {
+        Some(token) => Client::new_admin(username.clone(), token, addr, save_directory).await,
+        None => Client::new(username.clone(), addr, save_directory).await,
+    };
+    let client = client
+        .unwrap()
+        .with_sender_dirs(sender_dirs)
+        .with_allowed_extensions(allow_ext)
+        .with_denied_extensions(deny_ext);
 
     // Terminal initialization
     let stdout = io::stdout().into_raw_mode()?;
@@ -53,23 +94,30 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let stdout = AlternateScreen::from(stdout);
     let backend = TermionBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
+    // pasted text comes marked, so its newlines don't send it line by line
+    write!(terminal.backend_mut(), "\x1b[?2004h")?;
 
     let mut events = Events::new(client);
     let mut messages = vec![];
-    let mut curr_text = String::new();
+    let mut images: Vec<(String, String, Vec<u8>)> = vec![];
+    let mut offers: Vec<String> = vec![];
+    // false once we were kicked or banned
+    let mut connected = true;
+    let mut editor = Editor::default();
+    let mut pasting = false;
 
     let mut offset = 0u16;
 
     loop {
-        let p_m = messages.iter().cloned().collect::<Vec<_>>();
+        let p_m = messages.to_vec();
         terminal.draw(|f| {
             let chunks = Layout::default()
                 .direction(Direction::Vertical)
                 .constraints(
                     [
                         Constraint::Percentage(10),
-                        Constraint::Percentage(80),
-                        Constraint::Percentage(10),
+                        Constraint::Min(0),
+                        Constraint::Length(editor.lines().min(MAX_INPUT_LINES) as u16 + 2),
                     ]
                     .as_ref(),
                 )
@@ -84,49 +132,110 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .alignment(Alignment::Left)
                 .scroll((offset, 0))
                 .wrap(Wrap { trim: true });
-            let type_area = Paragraph::new(Span::raw(curr_text.as_str()))
+            // the cursor stays in view, scrolling the text if it doesn't fit
+            let input = chunks[2];
+            let (column, line) = editor.cursor();
+            let scroll_x = column.saturating_sub(input.width.saturating_sub(3) as usize) as u16;
+            let scroll_y = line.saturating_sub(input.height.saturating_sub(3) as usize) as u16;
+            let type_area = Paragraph::new(Text::raw(editor.text()))
                 .block(
                     Block::default()
-                        .title("Type your message here")
+                        .title("Type your message here, Alt+Enter for a new line")
                         .borders(Borders::ALL),
                 )
                 .alignment(Alignment::Left)
-                .wrap(Wrap { trim: true });
+                .scroll((scroll_y, scroll_x));
+            f.set_cursor(
+                input.x + 1 + column as u16 - scroll_x,
+                input.y + 1 + line as u16 - scroll_y,
+            );
             f.render_widget(title_area, chunks[0]);
             f.render_widget(messages_area, chunks[1]);
             f.render_widget(type_area, chunks[2]);
         })?;
 
         match events.next()? {
-            Event::Input(Key::Char('\n')) => {
-                lazy_static::lazy_static! {
-                    static ref RE: Regex = Regex::new(r"((/file (?P<file>((?:[a-zA-Z]|\\)(\\[\w\- \.:]+\.(\w+))|((/[\w\- \.:]+)+)))$)|(?P<msg>.*))").unwrap();
+            Event::Paste(started) => pasting = started,
+            Event::Input(Key::Char('\n')) if pasting => editor.insert('\n'),
+            Event::Input(Key::Alt('\r')) | Event::Input(Key::Alt('\n')) => editor.insert('\n'),
+            Event::Input(Key::Char('\n')) if !connected => {
+                messages.push(notice("Disconnected from the server.".to_string()));
+                editor.take();
+            }
+            Event::Input(Key::Char('\n')) => match Command::parse(&editor.take()) {
+                Command::Text(message) => events.send(message).await,
+                Command::File(file) => {
+                    if let Err(e) = events.send_file(file).await {
+                        messages.push(notice(format!("Can't send file: {}", e)));
+                    }
                 }
-                let (file, message) = {
-                    let c = RE.captures(&curr_text).unwrap();
-                    (
-                        c.name("file").map(|m| PathBuf::from(m.as_str())),
-                        c.name("msg").map(|m| m.as_str().to_string()),
-                    )
-                };
-                if let Some(file) = file {
-                    events.send_file(file).await;
-                } else {
-                    events.send(message.unwrap()).await;
+                Command::Image(file) => {
+                    if let Err(e) = events.send_image(file).await {
+                        messages.push(notice(format!("Can't send image: {}", e)));
+                    }
                 }
-                curr_text.clear();
-            }
-            Event::Input(Key::Backspace) => {
-                curr_text.pop();
-            }
-            Event::Input(Key::Char(ch)) => {
-                curr_text.push(ch);
-            }
-            Event::Input(Key::Down) => {
-                offset += 1;
+                Command::Voice(file) => {
+                    if let Err(e) = events.send_voice(file).await {
+                        messages.push(notice(format!("Can't send voice note: {}", e)));
+                    }
+                }
+                Command::Accept(n) => {
+                    let id = match n {
+                        Some(n) => offers.get(n),
+                        None => offers.last(),
+                    };
+                    match id {
+                        Some(id) => events.accept_file(id.clone()).await,
+                        None => messages.push(notice("No such file offer.".to_string())),
+                    }
+                }
+                Command::Save(n) => {
+                    let image = match n {
+                        Some(n) => images.get(n),
+                        None => images.last(),
+                    };
+                    let line = match image {
+                        Some((from, filename, content)) => {
+                            match events.save(from, filename, content).await {
+                                Ok(path) => format!("Image saved to {}", path.display()),
+                                Err(e) => format!("Can't save image: {}", e),
+                            }
+                        }
+                        None => "No such image.".to_string(),
+                    };
+                    messages.push(notice(line));
+                }
+                Command::Usage => events.request_usage().await,
+                Command::Kick { user, reason } => events.kick(user, reason).await,
+                Command::Ban { user, reason } => events.ban(user, reason).await,
+                Command::Unban(user) => events.unban(user).await,
+                Command::Mute { user, minutes } => {
+                    let duration = minutes.map(|m| Duration::from_secs(m.saturating_mul(60)));
+                    events.mute(user, duration).await
+                }
+                Command::Unmute(user) => events.unmute(user).await,
+                Command::Direct { to, text } => {
+                    match events.send_direct(to.clone(), text.clone()).await {
+                        Ok(()) => messages.push(direct_line(&format!("to {}", to), text)),
+                        Err(e) => messages.push(notice(format!("Can't send message: {}", e))),
+                    }
+                }
+            },
+            Event::Input(Key::Backspace) => editor.backspace(),
+            Event::Input(Key::Delete) => editor.delete(),
+            Event::Input(Key::Ctrl('w')) | Event::Input(Key::Alt('\x7f')) => editor.delete_word(),
+            Event::Input(Key::Left) => editor.left(),
+            Event::Input(Key::Right) => editor.right(),
+            Event::Input(Key::Home) | Event::Input(Key::Ctrl('a')) => editor.home(),
+            Event::Input(Key::End) | Event::Input(Key::Ctrl('e')) => editor.end(),
+            Event::Input(Key::Up) => editor.up(),
+            Event::Input(Key::Down) => editor.down(),
+            Event::Input(Key::Char(ch)) => editor.insert(ch),
+            Event::Input(Key::PageDown) => {
+                offset = offset.saturating_add(SCROLL_PAGE);
             }
-            Event::Input(Key::Up) => {
-                offset = offset.saturating_sub(1);
+            Event::Input(Key::PageUp) => {
+                offset = offset.saturating_sub(SCROLL_PAGE);
             }
             Event::Input(Key::Esc) => {
                 break;
@@ -138,7 +247,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     .time()
                     .format("%H:%M:%S")
                     .to_string();
-                let user: String = msg.from.into();
+                let user = msg.from;
+                let action = msg.action;
                 let user_color = if user == username {
                     Color::Yellow
                 } else {
@@ -152,25 +262,199 @@ async fn main() -> Result<(), Box<dyn Error>> {
                                 Style::default().add_modifier(Modifier::BOLD),
                             ),
                             Span::styled(
-                                format!("[{}] send file: ", user),
+                                format!("[{}] file received: ", user),
                                 Style::default().fg(user_color),
                             ),
                             Span::styled(
                                 msg.filename.unwrap(),
                                 Style::default().add_modifier(Modifier::ITALIC),
                             ),
+                            Span::raw(match &msg.saved {
+                                Some(path) => format!(" -> {}", path.display()),
+                                None => " (not saved)".to_string(),
+                            }),
                         ]));
                     }
+                    MessageType::FileOffer => {
+                        let offer = msg.offer.unwrap();
+                        messages.push(Spans::from(vec![
+                            Span::styled(
+                                format!("<{}> ", time),
+                                Style::default().add_modifier(Modifier::BOLD),
+                            ),
+                            Span::styled(
+                                format!("[{}] offers file #{}: ", user, offers.len()),
+                                Style::default().fg(user_color),
+                            ),
+                            Span::styled(
+                                msg.filename.unwrap(),
+                                Style::default().add_modifier(Modifier::ITALIC),
+                            ),
+                            Span::raw(format!(
+                                " ({} bytes), type /accept {} to download",
+                                offer.size,
+                                offers.len()
+                            )),
+                        ]));
+                        offers.push(offer.id);
+                    }
+                    MessageType::ChecksumMismatch => {
+                        messages.push(notice(
+                            "File transfer failed: checksum mismatch.".to_string(),
+                        ));
+                    }
+                    MessageType::QuotaExceeded => {
+                        messages.push(notice(format!(
+                            "Upload refused: {}.",
+                            String::from_utf8_lossy(&msg.content)
+                        )));
+                    }
+                    MessageType::Forbidden => {
+                        let line = if msg.content.is_empty() {
+                            "Only admins can do that.".to_string()
+                        } else {
+                            format!("Not allowed: {}.", String::from_utf8_lossy(&msg.content))
+                        };
+                        messages.push(notice(line));
+                    }
+                    MessageType::Kick
+                    | MessageType::Ban
+                    | MessageType::Mute
+                    | MessageType::Unban
+                    | MessageType::Unmute => {
+                        let moderation = msg.moderation.unwrap();
+                        let action = match msg.desc.r#type {
+                            MessageType::Kick => "kicked",
+                            MessageType::Ban => "banned",
+                            MessageType::Mute => "muted",
+                            MessageType::Unban => "unbanned",
+                            _ => "unmuted",
+                        };
+                        let by = if user == SYSTEM_USER {
+                            "the server"
+                        } else {
+                            &user
+                        };
+                        let mut line = format!("{} was {} by {}", moderation.user, action, by);
+                        if let Some(secs) = moderation.duration_secs {
+                            line.push_str(&format!(" for {} min", secs / 60));
+                        }
+                        if let Some(reason) = moderation.reason {
+                            line.push_str(&format!(": {}", reason));
+                        }
+                        line.push('.');
+                        messages.push(notice(line));
+                        let removed =
+                            matches!(msg.desc.r#type, MessageType::Kick | MessageType::Ban);
+                        if removed && moderation.user == username {
+                            connected = false;
+                        }
+                    }
+                    MessageType::ServerNotice => {
+                        let text = String::from_utf8_lossy(&msg.content);
+                        messages.extend(text.lines().map(|line| server_line(line.to_string())));
+                    }
+                    MessageType::Usage => {
+                        if let Ok(report) = serde_json::from_slice::<UsageReport>(&msg.content) {
+                            usage_lines(&report, &mut messages);
+                        }
+                    }
+                    MessageType::NoSuchFile => {
+                        messages.push(notice("The file is no longer available.".to_string()));
+                    }
                     MessageType::Utf8 => {
+                        let text = String::from_utf8(msg.content).unwrap();
+                        let (from, style) = if action {
+                            let style = Style::default().add_modifier(Modifier::ITALIC);
+                            (format!("* {} ", user), style)
+                        } else {
+                            (format!("[{}]: ", user), Style::default())
+                        };
+                        let head = vec![
+                            Span::styled(
+                                format!("<{}> ", time),
+                                Style::default().add_modifier(Modifier::BOLD),
+                            ),
+                            Span::styled(from, Style::default().fg(user_color)),
+                        ];
+                        messages.extend(markup_lines(head, &text, style, &username));
+                        if user != username && markup::mentions(&text).contains(&username.as_str())
+                        {
+                            // ring the terminal bell
+                            terminal.backend_mut().write_all(b"\x07")?;
+                            terminal.backend_mut().flush()?;
+                        }
+                    }
+                    MessageType::Direct => {
+                        let text = String::from_utf8_lossy(&msg.content).into_owned();
+                        messages.push(direct_line(&format!("from {}", user), text));
+                    }
+                    MessageType::BadDirect => {
+                        messages.push(notice(format!(
+                            "Can't decrypt a direct message from {} or their key changed.",
+                            user
+                        )));
+                    }
+                    MessageType::NoSuchUser => {
+                        messages.push(notice(format!(
+                            "No such user: {}.",
+                            msg.to.unwrap_or_default()
+                        )));
+                    }
+                    MessageType::Image => {
+                        let info = msg.image.unwrap();
+                        let ext = info.mime.trim_start_matches("image/");
+                        let filename =
+                            format!("{}-{}.{}", user, msg.timestamp.format("%Y%m%d%H%M%S"), ext);
+                        messages.push(Spans::from(vec![
+                            Span::styled(
+                                format!("<{}> ", time),
+                                Style::default().add_modifier(Modifier::BOLD),
+                            ),
+                            Span::styled(
+                                format!("[{}] send image #{}: ", user, images.len()),
+                                Style::default().fg(user_color),
+                            ),
+                            Span::styled(
+                                format!("{}x{} {}", info.width, info.height, info.mime),
+                                Style::default().add_modifier(Modifier::ITALIC),
+                            ),
+                        ]));
+                        if let Some(thumb) = Thumbnail::new(&msg.content, 32, 32) {
+                            messages.extend(thumbnail_lines(&thumb));
+                        }
+                        images.push((user, filename, msg.content));
+                    }
+                    MessageType::Voice => {
+                        let info = msg.voice.unwrap();
+                        let secs = info.duration_ms / 1000;
+                        let saved = match &msg.saved {
+                            Some(path) => path.display().to_string(),
+                            None => "not saved".to_string(),
+                        };
                         messages.push(Spans::from(vec![
                             Span::styled(
                                 format!("<{}> ", time),
                                 Style::default().add_modifier(Modifier::BOLD),
                             ),
-                            Span::styled(format!("[{}]: ", user), Style::default().fg(user_color)),
-                            Span::raw(String::from_utf8(msg.content).unwrap()),
+                            Span::styled(
+                                format!(
+                                    "[{}] send voice note ({}:{:02}): ",
+                                    user,
+                                    secs / 60,
+                                    secs % 60
+                                ),
+                                Style::default().fg(user_color),
+                            ),
+                            Span::styled(saved, Style::default().add_modifier(Modifier::ITALIC)),
                         ]));
                     }
+                    MessageType::BadVoice => {
+                        messages.push(notice("Server rejected the voice note.".to_string()));
+                    }
+                    MessageType::BadImage => {
+                        messages.push(notice("Server rejected the image.".to_string()));
+                    }
                     MessageType::Login => {
                         messages.push(Spans::from(vec![
                             Span::styled(
@@ -181,6 +465,14 @@ async fn main() -> Result<(), Box<dyn Error>> {
                             Span::styled(user, Style::default().fg(Color::Red)),
                         ]));
                     }
+                    MessageType::Nick => {
+                        let new = String::from_utf8(msg.content).unwrap_or_default();
+                        messages.push(notice(format!("{} is now known as {}.", user, new)));
+                        if user == username {
+                            title_text = format!("{} as {}", address, new);
+                            username = new;
+                        }
+                    }
                     MessageType::Logout => {
                         messages.push(Spans::from(vec![
                             Span::styled(
@@ -198,5 +490,122 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
+    write!(terminal.backend_mut(), "\x1b[?2004l")?;
     Ok(())
 }
+
+fn usage_lines(report: &UsageReport, messages: &mut Vec<Spans>) {
+    let limit = |quota: Option<u64>| quota.map_or("unlimited".to_string(), |q| q.to_string());
+    messages.push(notice(format!(
+        "Storage: {} bytes used of {}, {} bytes free on disk, {} per user.",
+        report.total,
+        limit(report.global_quota),
+        report.available,
+        limit(report.user_quota)
+    )));
+    for (user, bytes) in &report.users {
+        messages.push(notice(format!("  {}: {} bytes", user, bytes)));
+    }
+}
+
+/// Lines of a text message with its markup rendered, `head` starts the first one.
+fn markup_lines(
+    head: Vec<Span<'static>>,
+    text: &str,
+    style: Style,
+    me: &str,
+) -> Vec<Spans<'static>> {
+    let code = Style::default().fg(Color::Cyan);
+    let mut lines = Vec::new();
+    for block in markup::parse(text) {
+        match block {
+            markup::Block::Line(inlines) => lines.push(
+                inlines
+                    .into_iter()
+                    .map(|inline| inline_span(inline, style, me))
+                    .collect(),
+            ),
+            // a bar rather than indentation, which wrapping would trim
+            markup::Block::Code {
+                lines: code_lines, ..
+            } => lines.extend(code_lines.into_iter().map(|line| {
+                vec![
+                    Span::styled("| ", Style::default().fg(Color::DarkGray)),
+                    Span::styled(line.to_string(), code),
+                ]
+            })),
+        }
+    }
+    let mut lines = lines.into_iter();
+    let mut first = head;
+    first.extend(lines.next().unwrap_or_default());
+    std::iter::once(first)
+        .chain(lines)
+        .map(Spans::from)
+        .collect()
+}
+
+fn inline_span(inline: Inline, style: Style, me: &str) -> Span<'static> {
+    match inline {
+        Inline::Plain(text) => Span::styled(text.to_string(), style),
+        Inline::Bold(text) => Span::styled(text.to_string(), style.add_modifier(Modifier::BOLD)),
+        Inline::Italic(text) => {
+            Span::styled(text.to_string(), style.add_modifier(Modifier::ITALIC))
+        }
+        Inline::Code(text) => Span::styled(text.to_string(), style.fg(Color::Cyan)),
+        Inline::Mention(user) => {
+            let mut mention = style.fg(Color::Green).add_modifier(Modifier::BOLD);
+            if user == me {
+                mention = mention.add_modifier(Modifier::REVERSED);
+            }
+            Span::styled(format!("@{}", user), mention)
+        }
+    }
+}
+
+fn direct_line(peer: &str, text: String) -> Spans<'static> {
+    Spans::from(vec![
+        Span::styled(
+            format!("[DM {}]: ", peer),
+            Style::default()
+                .fg(Color::Magenta)
+                .add_modifier(Modifier::BOLD),
+        ),
+        Span::raw(text),
+    ])
+}
+
+/// Messages from the server itself, set apart from anything a user could write.
+fn server_line(text: String) -> Spans<'static> {
+    let style = Style::default().fg(Color::Yellow);
+    Spans::from(vec![
+        Span::styled("-!- ", style.add_modifier(Modifier::BOLD)),
+        Span::styled(text, style),
+    ])
+}
+
+fn notice(text: String) -> Spans<'static> {
+    Spans::from(Span::styled(text, Style::default().fg(Color::DarkGray)))
+}
+
+/// Renders a thumbnail with half-block characters: every terminal cell shows
+/// two vertically stacked pixels, the upper one as foreground and the lower one as background.
+fn thumbnail_lines(thumb: &Thumbnail) -> Vec<Spans<'static>> {
+    let rgb = |[r, g, b]: [u8; 3]| Color::Rgb(r, g, b);
+    (0..thumb.height)
+        .step_by(2)
+        .map(|y| {
+            Spans::from(
+                (0..thumb.width)
+                    .map(|x| {
+                        let mut style = Style::default().fg(rgb(thumb.pixel(x, y).unwrap()));
+                        if let Some(lower) = thumb.pixel(x, y + 1) {
+                            style = style.bg(rgb(lower));
+                        }
+                        Span::styled("\u{2580}", style)
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .collect()
+}
@@ -1,16 +1,185 @@
-use chat::server;
+use std::{path::PathBuf, str::FromStr, sync::Arc};
+
+use chat::server::{self, Commands, ConfigError, ConfigFile, ServerConfig, Webhook};
 use structopt::StructOpt;
+use tokio::net::TcpListener;
+use tracing_subscriber::EnvFilter;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:8080";
+
+#[derive(Debug, Clone, Copy)]
+enum LogFormat {
+    Plain,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(Self::Plain),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown log format {}", s)),
+        }
+    }
+}
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "server", about = "Simple TCP chat room.")]
 struct Opt {
-    /// Set address of the server
-    #[structopt(short, long, default_value = "127.0.0.1:8080")]
-    address: String,
+    /// TOML configuration file, reloaded on SIGHUP
+    #[structopt(short, long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Set address of the server, may be repeated [default: 127.0.0.1:8080]
+    #[structopt(short, long)]
+    address: Vec<String>,
+    /// Directory for uploads being relayed or offered
+    #[structopt(long, parse(from_os_str))]
+    spool_dir: Option<PathBuf>,
+    /// Maximum bytes a single user may keep in the spool
+    #[structopt(long)]
+    user_quota: Option<u64>,
+    /// Maximum bytes all users together may keep in the spool
+    #[structopt(long)]
+    global_quota: Option<u64>,
+    /// Refuse uploads that would leave less free disk space than this, in bytes
+    #[structopt(long)]
+    min_free_space: Option<u64>,
+    /// Largest file, image or voice message accepted, in bytes
+    #[structopt(long)]
+    max_upload_size: Option<u64>,
+    /// How long offered files are kept, in seconds
+    #[structopt(long)]
+    offer_ttl: Option<u64>,
+    /// How many recent messages new users get, at most 127
+    #[structopt(long)]
+    history_depth: Option<usize>,
+    /// Username allowed to run admin commands, may be repeated
+    #[structopt(long = "admin")]
+    admins: Vec<String>,
+    /// Token admins must present at login
+    #[structopt(long)]
+    admin_token: Option<String>,
+    /// Message of the day
+    #[structopt(long)]
+    motd: Option<String>,
+    /// File with banned users and addresses
+    #[structopt(long, parse(from_os_str))]
+    ban_file: Option<PathBuf>,
+    /// Unix socket to listen on for chatctl
+    #[structopt(long, parse(from_os_str))]
+    control_socket: Option<PathBuf>,
+    /// Address to serve Prometheus metrics on, at /metrics
+    #[structopt(long)]
+    metrics_address: Option<String>,
+    /// Address to accept WebSocket clients on
+    #[structopt(long)]
+    websocket_address: Option<String>,
+    /// Address to accept clients speaking JSON lines on
+    #[structopt(long)]
+    json_address: Option<String>,
+    /// Address to accept IRC clients on
+    #[structopt(long)]
+    irc_address: Option<String>,
+    /// Name of this server, its users show up on linked servers as user@name
+    #[structopt(long)]
+    server_name: Option<String>,
+    /// Address to accept links from other servers on
+    #[structopt(long)]
+    link_address: Option<String>,
+    /// Server to keep a link to, may be repeated
+    #[structopt(long = "link")]
+    links: Vec<String>,
+    /// Secret required from and presented to linked servers
+    #[structopt(long)]
+    link_secret: Option<String>,
+    /// Address of the HTTP endpoint bots post messages to, bots are set in the configuration file
+    #[structopt(long)]
+    bot_address: Option<String>,
+    /// URL to post every broadcast message to, may be repeated
+    #[structopt(long = "webhook")]
+    webhooks: Vec<String>,
+    /// Never compress message contents
+    #[structopt(long)]
+    no_compression: bool,
+    /// Log output, filtered by RUST_LOG
+    #[structopt(long, default_value = "plain", possible_values = &["plain", "json"])]
+    log_format: LogFormat,
+}
+
+impl Opt {
+    /// Reads the configuration file, flags given on the command line take precedence.
+    fn load(&self) -> Result<(ServerConfig, Vec<String>), ConfigError> {
+        let mut config = ServerConfig::default();
+        let mut bind = Vec::new();
+        if let Some(path) = &self.config {
+            let file = ConfigFile::load(path)?;
+            bind = file.bind.clone();
+            file.apply(&mut config);
+        }
+        let flags = ConfigFile {
+            bind: Vec::new(),
+            spool_dir: self.spool_dir.clone(),
+            user_quota: self.user_quota,
+            global_quota: self.global_quota,
+            min_free_space: self.min_free_space,
+            max_upload_size: self.max_upload_size,
+            offer_ttl: self.offer_ttl,
+            history_depth: self.history_depth,
+            admins: (!self.admins.is_empty()).then(|| self.admins.clone()),
+            admin_token: self.admin_token.clone(),
+            motd: self.motd.clone(),
+            ban_file: self.ban_file.clone(),
+            control_socket: self.control_socket.clone(),
+            metrics_address: self.metrics_address.clone(),
+            websocket_address: self.websocket_address.clone(),
+            json_address: self.json_address.clone(),
+            irc_address: self.irc_address.clone(),
+            server_name: self.server_name.clone(),
+            link_address: self.link_address.clone(),
+            links: (!self.links.is_empty()).then(|| self.links.clone()),
+            link_secret: self.link_secret.clone(),
+            bot_address: self.bot_address.clone(),
+            bots: None,
+            webhooks: (!self.webhooks.is_empty())
+                .then(|| self.webhooks.iter().cloned().map(Webhook::new).collect()),
+            compression: self.no_compression.then(Vec::new),
+        };
+        flags.apply(&mut config);
+        if !self.address.is_empty() {
+            bind = self.address.clone();
+        }
+        if bind.is_empty() {
+            bind.push(DEFAULT_ADDRESS.to_string());
+        }
+        Ok((config, bind))
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    let Opt { address } = Opt::from_args();
-    server::run_server(address).await.unwrap();
+    let opt = Arc::new(Opt::from_args());
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match opt.log_format {
+        LogFormat::Plain => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+
+    let (config, bind) = match opt.load() {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            tracing::error!(error = %e, "failed to read the configuration");
+            std::process::exit(1);
+        }
+    };
+    let mut listeners = Vec::new();
+    for address in bind {
+        listeners.push(TcpListener::bind(address).await.unwrap());
+    }
+    let reload: server::Reload = Arc::new(move || opt.load().map(|(config, _)| config));
+    server::run_server_on(listeners, config, Some(reload), Commands::default())
+        .await
+        .unwrap();
 }
@@ -1,17 +1,31 @@
-use std::{path::PathBuf, pin::Pin};
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
 
 use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
 use tokio::{
     fs::{File, OpenOptions},
-    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter},
     net::{TcpStream, ToSocketAddrs},
     sync::{
         mpsc::{channel, Receiver, Sender},
-        Mutex,
+        oneshot, Mutex,
     },
 };
 
-use crate::{Descriptor, MessageType, ServerHeader};
+use crate::{
+    compression::{self, Compression},
+    crypto::KeyPair,
+    media::{ImageInfo, VoiceInfo},
+    sha256, sha256_file, Descriptor, Direct, FileOffer, LoginInfo, MessageType, Moderation,
+    PublicKey, ServerHeader, Transfer, CHUNK_SIZE,
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -20,6 +34,39 @@ pub enum Error {
 
     #[error("Bad username")]
     BadUsername,
+
+    #[error("Username is already taken")]
+    UsernameExists,
+
+    #[error("Unsupported image format")]
+    UnsupportedImage,
+
+    #[error("Unsupported voice format, expected PCM WAV")]
+    UnsupportedVoice,
+
+    #[error("File extension is not allowed: {0}")]
+    ExtensionNotAllowed(String),
+
+    #[error("Checksum mismatch")]
+    ChecksumMismatch,
+
+    #[error("Server refused the upload")]
+    UploadRefused,
+
+    #[error("Banned from the server")]
+    Banned,
+
+    #[error("Wrong admin token")]
+    BadAdminToken,
+
+    #[error("No such user: {0}")]
+    NoSuchUser(String),
+
+    #[error("Public key of {0} is invalid")]
+    BadKey(String),
+
+    #[error("Public key of {0} changed since it was first seen")]
+    KeyChanged(String),
 }
 
 #[derive(Debug)]
@@ -28,13 +75,38 @@ pub struct ServerMessage {
     pub timestamp: DateTime<Utc>,
     pub from: String,
     pub filename: Option<String>,
+    pub image: Option<ImageInfo>,
+    pub voice: Option<VoiceInfo>,
+    pub offer: Option<FileOffer>,
+    pub transfer: Option<Transfer>,
+    /// Set for direct messages, whose content is already decrypted.
+    pub direct: Option<Direct>,
+    /// The user a `NoSuchUser` reply is about.
+    pub to: Option<String>,
+    pub moderation: Option<Moderation>,
+    /// Set for text sent with `/me`.
+    pub action: bool,
     pub content: Vec<u8>,
+    /// Where the content was saved, for messages that are stored on receipt.
+    pub saved: Option<PathBuf>,
 }
 
 pub struct Client {
     reciever: Mutex<Receiver<ServerMessage>>,
     sender: Mutex<Sender<ClientMessage>>,
     save_dir: PathBuf,
+    /// Download attempts by offer id, to retry on checksum mismatch.
+    downloads: Mutex<HashMap<String, u8>>,
+    sender_dirs: bool,
+    allowed_extensions: Vec<String>,
+    denied_extensions: Vec<String>,
+    keys: KeyPair,
+    /// The name we are logged in as, follows `/nick`.
+    name: Arc<Mutex<String>>,
+    directory: Directory,
+    /// Public keys trusted on first use, by username.
+    pins: Mutex<HashMap<String, String>>,
+    pins_path: PathBuf,
 }
 
 impl Client {
@@ -42,6 +114,25 @@ impl Client {
         uname: String,
         addr: impl ToSocketAddrs,
         save_dir: PathBuf,
+    ) -> Result<Self, Error> {
+        Self::login(uname, None, addr, save_dir).await
+    }
+
+    /// Logs in as an admin, the server refuses the login if `admin_token` is wrong.
+    pub async fn new_admin(
+        uname: String,
+        admin_token: String,
+        addr: impl ToSocketAddrs,
+        save_dir: PathBuf,
+    ) -> Result<Self, Error> {
+        Self::login(uname, Some(admin_token), addr, save_dir).await
+    }
+
+    async fn login(
+        uname: String,
+        admin_token: Option<String>,
+        addr: impl ToSocketAddrs,
+        save_dir: PathBuf,
     ) -> Result<Self, Error> {
         let (reader, writer) = TcpStream::connect(addr).await?.into_split();
         let mut reader = BufReader::new(reader);
@@ -49,73 +140,316 @@ impl Client {
         let (tx_c, mut rx_c) = channel(128);
         let (tx_s, rx_s) = channel(128);
 
+        // the server picks one of the offered algorithms and may use it for any message
+        let offered = serde_json::to_vec(&LoginInfo {
+            compression: Compression::SUPPORTED.to_vec(),
+            admin_token,
+        })
+        .unwrap();
         writer
             .write_all(
                 Descriptor::from(MessageType::Login)
                     .with_header_len(uname.len() as u16)
+                    .with_content_len(offered.len() as u64)
                     .as_bytes(),
             )
             .await?;
         writer.write_all(uname.as_bytes()).await?;
+        writer.write_all(&offered).await?;
         writer.flush().await?;
 
         let desc = Descriptor::read(Pin::new(&mut reader)).await?;
-        if desc.r#type != MessageType::Login {
-            return Err(Error::BadUsername);
+        match desc.r#type {
+            MessageType::Login => {}
+            MessageType::Ban => return Err(Error::Banned),
+            MessageType::Forbidden => return Err(Error::BadAdminToken),
+            MessageType::UsernameExists => return Err(Error::UsernameExists),
+            _ => return Err(Error::BadUsername),
         }
+        let compression = desc.compression;
 
+        // publish our key so others can send us direct messages
+        let keys = load_keys(&save_dir.join(format!(".{}.key", sanitize_filename(&uname)))).await?;
+        let pins_path = save_dir.join(format!(".{}.known_keys", sanitize_filename(&uname)));
+        let pins = load_pins(&pins_path).await?;
+        let key = PublicKey {
+            user: uname.clone(),
+            key: keys.public_hex(),
+        };
+        let header = serde_json::to_vec(&key).unwrap();
+        write_msg(
+            &mut writer,
+            MessageType::PublicKey,
+            &header,
+            &[],
+            compression,
+        )
+        .await?;
+
+        let waiters = Waiters::default();
+        let status_waiters = Arc::clone(&waiters);
+        let pending = Pending::default();
+        let confirmed = Arc::clone(&pending);
+        let retry = tx_c.clone();
+        let uploader = Arc::new(uname.clone());
+        let directory = Directory::default();
+        let known_keys = Arc::clone(&directory);
+        let name = Arc::new(Mutex::new(uname.clone()));
+        let renamed = Arc::clone(&name);
+        // uploads run in their own tasks and hand chunks over to the writer,
+        // which sends them only when there are no other messages waiting
+        let (tx_f, mut rx_f) = channel::<Frame>(4);
+        let upload_notices = tx_s.clone();
         tokio::spawn(async move {
-            while let Some(msg) = rx_c.recv().await {
-                match msg {
-                    ClientMessage::File(path) => {
-                        let file = File::open(&path).await.unwrap();
-                        let filename = path.file_name().unwrap().to_string_lossy();
-                        writer
-                            .write_all(
-                                Descriptor::from(MessageType::File)
-                                    .with_header_len(filename.len() as u16)
-                                    .with_content_len(file.metadata().await.unwrap().len() as u64)
-                                    .as_bytes(),
-                            )
+            loop {
+                let msg = tokio::select! {
+                    biased;
+                    msg = rx_c.recv() => match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    },
+                    Some((r#type, header, content)) = rx_f.recv() => {
+                        write_msg(&mut writer, r#type, &header, &content, compression)
                             .await
                             .unwrap();
-                        writer.write_all(filename.as_bytes()).await.unwrap();
-                        let mut reader = BufReader::new(file);
-                        let mut buf = Vec::with_capacity(1024);
-                        while reader.read_buf(&mut buf).await.unwrap() != 0 {
-                            writer.write_all(&buf).await.unwrap();
-                            buf.clear();
-                        }
-                        writer.flush().await.unwrap();
+                        continue;
+                    }
+                };
+                match msg {
+                    ClientMessage::Close => {
+                        let _ = writer.shutdown().await;
+                        break;
+                    }
+                    ClientMessage::Upload(upload) => {
+                        let frames = tx_f.clone();
+                        let uploader = Arc::clone(&uploader);
+                        let waiters = Arc::clone(&waiters);
+                        let pending = Arc::clone(&pending);
+                        let notices = upload_notices.clone();
+                        tokio::spawn(async move {
+                            let path = upload.path.clone();
+                            // the server already told the user why it refused the upload
+                            match send_upload(&frames, upload, &uploader, &waiters, &pending).await
+                            {
+                                Ok(()) | Err(Error::UploadRefused) => {}
+                                // e.g. the file went away, tell the user instead of failing silently
+                                Err(e) => {
+                                    let text =
+                                        format!("Upload of {} failed: {}", path.display(), e);
+                                    let _ = notices.send(local_notice(text)).await;
+                                }
+                            }
+                        });
+                    }
+                    ClientMessage::Download(transfer) => {
+                        let header = serde_json::to_vec(&transfer).unwrap();
+                        write_msg(
+                            &mut writer,
+                            MessageType::Download,
+                            &header,
+                            &[],
+                            compression,
+                        )
+                        .await
+                        .unwrap();
                     }
                     ClientMessage::Utf8(text) => {
-                        writer
-                            .write_all(
-                                Descriptor::from(MessageType::Utf8)
-                                    .with_content_len(text.len() as u64)
-                                    .as_bytes(),
-                            )
+                        write_msg(
+                            &mut writer,
+                            MessageType::Utf8,
+                            &[],
+                            text.as_bytes(),
+                            compression,
+                        )
+                        .await
+                        .unwrap();
+                    }
+                    ClientMessage::KeyRequest(user) => {
+                        write_msg(
+                            &mut writer,
+                            MessageType::KeyRequest,
+                            user.as_bytes(),
+                            &[],
+                            compression,
+                        )
+                        .await
+                        .unwrap();
+                    }
+                    ClientMessage::Direct { header, content } => {
+                        write_msg(
+                            &mut writer,
+                            MessageType::Direct,
+                            &header,
+                            &content,
+                            compression,
+                        )
+                        .await
+                        .unwrap();
+                    }
+                    ClientMessage::Usage => {
+                        write_msg(&mut writer, MessageType::Usage, &[], &[], compression)
+                            .await
+                            .unwrap();
+                    }
+                    ClientMessage::Moderate { r#type, header } => {
+                        write_msg(&mut writer, r#type, &header, &[], compression)
                             .await
                             .unwrap();
-                        writer.write_all(text.as_bytes()).await.unwrap();
-                        writer.flush().await.unwrap();
                     }
                 }
             }
         });
 
         tokio::spawn(async move {
+            // follows `/nick`, so our own uploads are still recognized
+            let mut uname = uname;
             let mut buf = Vec::new();
+            // messages whose content is still arriving in chunks, by stream id
+            let mut streams = HashMap::<String, ServerMessage>::new();
             loop {
-                let (desc, header, content) =
-                    read_msg(Pin::new(&mut reader), &mut buf).await.unwrap();
+                // the server closed the connection, e.g. after a kick
+                let (desc, header, content) = match read_msg(Pin::new(&mut reader), &mut buf).await
+                {
+                    Ok(msg) => msg,
+                    Err(_) => break,
+                };
+                if desc.r#type == MessageType::Chunk {
+                    let id = header.transfer.as_ref().map(|t| t.id.as_str());
+                    if let Some(msg) = id.and_then(|id| streams.get_mut(id)) {
+                        msg.content.extend_from_slice(&content);
+                        if msg.content.len() as u64 >= msg.desc.content_len {
+                            let msg = streams.remove(id.unwrap()).unwrap();
+                            tx_s.send(msg).await.unwrap();
+                        }
+                        continue;
+                    }
+                }
+                match desc.r#type {
+                    MessageType::TransferStatus => {
+                        if let Some(transfer) = header.transfer {
+                            if let Some(waiter) = status_waiters.lock().await.remove(&transfer.id) {
+                                let _ = waiter.send(transfer);
+                            }
+                        }
+                        continue;
+                    }
+                    MessageType::PublicKey => {
+                        if let Some(PublicKey { user, key }) = header.key {
+                            let found = Lookup::Found(key.clone());
+                            let lookup = known_keys.lock().await.insert(user, found);
+                            if let Some(Lookup::Waiting(waiters)) = lookup {
+                                for waiter in waiters {
+                                    let _ = waiter.send(key.clone());
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                    // a user we asked a key for isn't online, unless it's about a direct message
+                    MessageType::NoSuchUser => {
+                        if let Some(to) = &header.to {
+                            let mut directory = known_keys.lock().await;
+                            if let Some(Lookup::Waiting(_)) = directory.get(to) {
+                                directory.insert(to.clone(), Lookup::Missing);
+                                continue;
+                            }
+                            directory.remove(to);
+                        }
+                    }
+                    // a user that logs in again may come with a new key
+                    MessageType::Login | MessageType::Logout => {
+                        known_keys.lock().await.remove(header.from);
+                    }
+                    // the key stays with the user, under the new name
+                    MessageType::Nick => {
+                        let new = String::from_utf8_lossy(&content).into_owned();
+                        let mut directory = known_keys.lock().await;
+                        if let Some(lookup) = directory.remove(header.from) {
+                            directory.insert(new.clone(), lookup);
+                        }
+                        if header.from == uname {
+                            *renamed.lock().await = new.clone();
+                            uname = new;
+                        }
+                    }
+                    // dropping the waiter makes the upload give up
+                    MessageType::QuotaExceeded | MessageType::Forbidden => {
+                        if let Some(transfer) = &header.transfer {
+                            status_waiters.lock().await.remove(&transfer.id);
+                        }
+                    }
+                    // the server got a corrupted upload, send it again unless we gave up already
+                    MessageType::ChecksumMismatch => {
+                        let hash = header.transfer.as_ref().and_then(|t| t.hash.as_ref());
+                        if let Some(hash) = hash {
+                            let mut pending = confirmed.lock().await;
+                            let again = match pending.get_mut(hash) {
+                                Some((msg, attempts)) if *attempts < MAX_RETRIES => {
+                                    *attempts += 1;
+                                    Some(msg.clone())
+                                }
+                                _ => None,
+                            };
+                            if let Some(msg) = again {
+                                drop(pending);
+                                // the writer may be waiting for us to read, so don't wait for it here
+                                let retry = retry.clone();
+                                tokio::spawn(async move {
+                                    let _ = retry.send(msg).await;
+                                });
+                                continue;
+                            }
+                            pending.remove(hash);
+                        }
+                    }
+                    // the server won't relay it, so there is nothing to retry
+                    MessageType::BadImage | MessageType::BadVoice => {
+                        let hash = header.transfer.as_ref().and_then(|t| t.hash.as_ref());
+                        if let Some(hash) = hash {
+                            confirmed.lock().await.remove(hash);
+                        }
+                    }
+                    // our own upload came back, so it doesn't need to be retried anymore
+                    MessageType::Image | MessageType::Voice | MessageType::FileOffer
+                        if header.from == uname =>
+                    {
+                        let hash = header
+                            .image
+                            .as_ref()
+                            .map(|i| &i.hash)
+                            .or_else(|| header.voice.as_ref().map(|v| &v.hash))
+                            .or_else(|| header.offer.as_ref().map(|o| &o.hash));
+                        if let Some(hash) = hash {
+                            confirmed.lock().await.remove(hash);
+                        }
+                    }
+                    _ => {}
+                }
                 let msg = ServerMessage {
                     desc,
                     timestamp: header.timestamp,
                     from: header.from.into(),
                     filename: header.filename.map(|v| v.into()),
+                    image: header.image,
+                    voice: header.voice,
+                    offer: header.offer,
+                    transfer: header.transfer,
+                    direct: header.direct,
+                    to: header.to,
+                    moderation: header.moderation,
+                    action: header.action,
                     content,
+                    saved: None,
                 };
+                if let Some(stream) = header.stream {
+                    let msg = ServerMessage {
+                        desc: msg.desc.with_content_len(stream.size),
+                        content: Vec::with_capacity(stream.size as usize),
+                        ..msg
+                    };
+                    streams.insert(stream.id, msg);
+                    continue;
+                }
                 tx_s.send(msg).await.unwrap();
             }
         });
@@ -124,21 +458,125 @@ impl Client {
             reciever: Mutex::new(rx_s),
             sender: Mutex::new(tx_c),
             save_dir,
+            downloads: Mutex::default(),
+            sender_dirs: false,
+            allowed_extensions: Vec::new(),
+            denied_extensions: Vec::new(),
+            keys,
+            name,
+            directory,
+            pins: Mutex::new(pins),
+            pins_path,
         })
     }
 
+    /// Save received files into a subdirectory named after the sender.
+    pub fn with_sender_dirs(mut self, sender_dirs: bool) -> Self {
+        self.sender_dirs = sender_dirs;
+        self
+    }
+
+    /// Only save received files with one of these extensions. Empty list allows everything.
+    pub fn with_allowed_extensions(mut self, extensions: Vec<String>) -> Self {
+        self.allowed_extensions = extensions.iter().map(|e| normalize_extension(e)).collect();
+        self
+    }
+
+    /// Never save received files with one of these extensions.
+    pub fn with_denied_extensions(mut self, extensions: Vec<String>) -> Self {
+        self.denied_extensions = extensions.iter().map(|e| normalize_extension(e)).collect();
+        self
+    }
+
+    /// Waits for the next message. Download chunks are stored as they arrive and
+    /// a completed download is returned as a `File` message with `saved` set.
+    /// Once the server closes the connection this never returns.
     pub async fn recv(&self) -> ServerMessage {
-        let msg = self.reciever.lock().await.recv().await.unwrap();
-        if msg.desc.r#type == MessageType::File {
-            let mut file = OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open(self.save_dir.join(msg.filename.as_ref().unwrap()))
-                .await
-                .unwrap();
-            file.write_all(&msg.content).await.unwrap();
+        match self.next_message().await {
+            Some(msg) => msg,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Like [`Client::recv`], but returns `None` once the server closed the connection.
+    pub async fn next_message(&self) -> Option<ServerMessage> {
+        let mut msg = loop {
+            let mut msg = self.reciever.lock().await.recv().await?;
+            if msg.desc.r#type != MessageType::Chunk {
+                break msg;
+            }
+            let id = msg.transfer.as_ref().unwrap().id.clone();
+            let stored = self.store_chunk(&msg).await;
+            if let Ok(None) = stored {
+                continue;
+            }
+            if let Err(Error::ChecksumMismatch) = stored {
+                let mut downloads = self.downloads.lock().await;
+                let attempts = downloads.entry(id.clone()).or_default();
+                if *attempts < MAX_RETRIES {
+                    *attempts += 1;
+                    drop(downloads);
+                    self.accept_file(id).await;
+                    continue;
+                }
+                downloads.remove(&id);
+                msg.desc = Descriptor::from(MessageType::ChecksumMismatch);
+                return Some(msg);
+            }
+            self.downloads.lock().await.remove(&id);
+            msg.desc = Descriptor::from(MessageType::File);
+            msg.filename = msg.transfer.as_ref().and_then(|t| t.filename.clone());
+            msg.saved = stored.ok().flatten();
+            msg.content.clear();
+            return Some(msg);
+        };
+
+        // media is relayed as is, so it can only be checked, not downloaded again
+        let expected = match msg.desc.r#type {
+            MessageType::Image => msg.image.as_ref().map(|i| &i.hash),
+            MessageType::Voice => msg.voice.as_ref().map(|v| &v.hash),
+            MessageType::File => msg.offer.as_ref().map(|o| &o.hash),
+            _ => None,
+        };
+        if expected.is_some_and(|hash| *hash != sha256(&msg.content)) {
+            msg.desc = Descriptor::from(MessageType::ChecksumMismatch);
+            return Some(msg);
+        }
+
+        if let Some(direct) = &msg.direct {
+            // the server could swap keys, so only the key the sender had first is accepted
+            let opened = match self.pin(&msg.from, &direct.key).await {
+                Err(Error::KeyChanged(_)) => None,
+                _ => self.keys.open(
+                    &direct.key,
+                    &msg.from,
+                    &direct.to,
+                    &direct.nonce,
+                    &msg.content,
+                ),
+            };
+            match opened {
+                Some(plaintext) => msg.content = plaintext,
+                None => {
+                    msg.desc = Descriptor::from(MessageType::BadDirect);
+                    msg.content.clear();
+                }
+            }
+            return Some(msg);
+        }
+
+        match msg.desc.r#type {
+            MessageType::File => {
+                let filename = msg.filename.as_deref().unwrap_or_default();
+                msg.saved = self.save(&msg.from, filename, &msg.content).await.ok();
+            }
+            MessageType::Voice => {
+                let filename = format!("{}-{}.wav", msg.from, msg.timestamp.format("%Y%m%d%H%M%S"));
+                msg.saved = self.save(&msg.from, &filename, &msg.content).await.ok();
+            }
+            _ => {}
         }
-        msg
+        Some(msg)
     }
 
     pub async fn send_text(&self, text: String) {
@@ -150,31 +588,653 @@ impl Client {
             .unwrap()
     }
 
-    pub async fn send_file(&self, path: PathBuf) {
+    /// Stops sending. The server handles everything sent so far and closes the connection,
+    /// so [`Client::next_message`] returns `None` after its last reply.
+    pub async fn close(&self) {
+        let _ = self.sender.lock().await.send(ClientMessage::Close).await;
+    }
+
+    /// Uploads a file in chunks. Sending the same file again after a dropped
+    /// connection resumes the upload from where the server left off.
+    pub async fn send_file(&self, path: PathBuf) -> Result<(), Error> {
+        if !tokio::fs::metadata(&path).await?.is_file() {
+            return Err(tokio::io::Error::from(ErrorKind::InvalidInput).into());
+        }
+        self.sender
+            .lock()
+            .await
+            .send(ClientMessage::Upload(Upload {
+                path,
+                image: None,
+                voice: None,
+            }))
+            .await
+            .unwrap();
+        Ok(())
+    }
+
+    /// Asks the server for an offered file. It arrives in chunks and is returned from
+    /// [`Client::recv`] as a `File` message once complete. A partially downloaded
+    /// file is resumed.
+    pub async fn accept_file(&self, id: String) {
+        let offset = file_len(&self.partial_path(&id)).await;
         self.sender
             .lock()
             .await
-            .send(ClientMessage::File(path))
+            .send(ClientMessage::Download(Transfer {
+                id,
+                offset,
+                ..Default::default()
+            }))
             .await
             .unwrap()
     }
+
+    /// Sends `text` to `to` only, encrypted with a key the server doesn't know.
+    pub async fn send_direct(&self, to: String, text: String) -> Result<(), Error> {
+        let their_key = self.public_key(&to).await?;
+        self.pin(&to, &their_key).await?;
+        let from = self.name.lock().await.clone();
+        let (nonce, content) = self
+            .keys
+            .seal(&their_key, &from, &to, text.as_bytes())
+            .ok_or_else(|| Error::BadKey(to.clone()))?;
+        let direct = Direct {
+            to,
+            key: self.keys.public_hex(),
+            nonce,
+        };
+        let header = serde_json::to_vec(&direct).unwrap();
+        self.sender
+            .lock()
+            .await
+            .send(ClientMessage::Direct { header, content })
+            .await
+            .unwrap();
+        Ok(())
+    }
+
+    /// Returns the public key of `user`, asking the server for it unless it is known already.
+    async fn public_key(&self, user: &str) -> Result<String, Error> {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut directory = self.directory.lock().await;
+            match directory.get_mut(user) {
+                Some(Lookup::Found(key)) => return Ok(key.clone()),
+                Some(Lookup::Waiting(waiters)) => waiters.push(tx),
+                _ => {
+                    directory.insert(user.to_string(), Lookup::Waiting(vec![tx]));
+                    self.sender
+                        .lock()
+                        .await
+                        .send(ClientMessage::KeyRequest(user.to_string()))
+                        .await
+                        .unwrap();
+                }
+            }
+        }
+        rx.await.map_err(|_| Error::NoSuchUser(user.to_string()))
+    }
+
+    /// Trusts the first key seen for `user` and refuses any other one later.
+    /// Pinned keys are kept in the save directory.
+    async fn pin(&self, user: &str, key: &str) -> Result<(), Error> {
+        pin_key(&mut *self.pins.lock().await, &self.pins_path, user, key).await
+    }
+
+    /// Disconnects `user`, only admins may do it.
+    pub async fn kick(&self, user: String, reason: Option<String>) {
+        self.moderate(MessageType::Kick, user, reason, None).await;
+    }
+
+    /// Disconnects `user` and keeps them and their address out, only admins may do it.
+    pub async fn ban(&self, user: String, reason: Option<String>) {
+        self.moderate(MessageType::Ban, user, reason, None).await;
+    }
+
+    pub async fn unban(&self, user: String) {
+        self.moderate(MessageType::Unban, user, None, None).await;
+    }
+
+    /// Stops `user` from sending messages for `duration` or until unmuted, only admins may do it.
+    pub async fn mute(&self, user: String, duration: Option<Duration>, reason: Option<String>) {
+        self.moderate(MessageType::Mute, user, reason, duration)
+            .await;
+    }
+
+    pub async fn unmute(&self, user: String) {
+        self.moderate(MessageType::Unmute, user, None, None).await;
+    }
+
+    async fn moderate(
+        &self,
+        r#type: MessageType,
+        user: String,
+        reason: Option<String>,
+        duration: Option<Duration>,
+    ) {
+        let moderation = Moderation {
+            user,
+            reason,
+            duration_secs: duration.map(|d| d.as_secs()),
+        };
+        let header = serde_json::to_vec(&moderation).unwrap();
+        self.sender
+            .lock()
+            .await
+            .send(ClientMessage::Moderate { r#type, header })
+            .await
+            .unwrap();
+    }
+
+    /// Sends an image, detecting its format and dimensions first. It is uploaded in chunks like a file.
+    pub async fn send_image(&self, path: PathBuf) -> Result<(), Error> {
+        let content = tokio::fs::read(&path).await?;
+        let info = ImageInfo::detect(&content).ok_or(Error::UnsupportedImage)?;
+        self.sender
+            .lock()
+            .await
+            .send(ClientMessage::Upload(Upload {
+                path,
+                image: Some(info),
+                voice: None,
+            }))
+            .await
+            .unwrap();
+        Ok(())
+    }
+
+    /// Asks the server for its storage usage report, only admins get it.
+    pub async fn request_usage(&self) {
+        self.sender
+            .lock()
+            .await
+            .send(ClientMessage::Usage)
+            .await
+            .unwrap();
+    }
+
+    /// Sends a voice note. Only PCM WAV recordings are accepted. It is uploaded in chunks like a file.
+    pub async fn send_voice(&self, path: PathBuf) -> Result<(), Error> {
+        let content = tokio::fs::read(&path).await?;
+        let info = VoiceInfo::detect(&content)
+            .filter(|info| info.validate(&content))
+            .ok_or(Error::UnsupportedVoice)?;
+        self.sender
+            .lock()
+            .await
+            .send(ClientMessage::Upload(Upload {
+                path,
+                image: None,
+                voice: Some(info),
+            }))
+            .await
+            .unwrap();
+        Ok(())
+    }
+
+    /// Writes `content` received from `from` into the save directory.
+    ///
+    /// `filename` is sanitized and never overwrites an existing file: on collision
+    /// a ` (N)` suffix is added before the extension.
+    pub async fn save(&self, from: &str, filename: &str, content: &[u8]) -> Result<PathBuf, Error> {
+        let (path, mut file) = self.create(from, filename).await?;
+        file.write_all(content).await?;
+        Ok(path)
+    }
+
+    /// Creates a new empty file for `filename` received from `from`, see [`Client::save`].
+    async fn create(&self, from: &str, filename: &str) -> Result<(PathBuf, File), Error> {
+        let filename = sanitize_filename(filename);
+        let (stem, ext) = match filename.rfind('.') {
+            Some(i) if i > 0 => filename.split_at(i),
+            _ => (filename.as_str(), ""),
+        };
+        let ext_key = normalize_extension(ext);
+        if (!self.allowed_extensions.is_empty() && !self.allowed_extensions.contains(&ext_key))
+            || self.denied_extensions.contains(&ext_key)
+        {
+            return Err(Error::ExtensionNotAllowed(filename));
+        }
+
+        let dir = if self.sender_dirs {
+            let dir = self.save_dir.join(sanitize_filename(from));
+            tokio::fs::create_dir_all(&dir).await?;
+            dir
+        } else {
+            self.save_dir.clone()
+        };
+
+        Ok(create_new(&dir, stem, ext).await?)
+    }
+
+    fn partial_path(&self, id: &str) -> PathBuf {
+        self.save_dir
+            .join(format!(".{}.part", sanitize_filename(id)))
+    }
+
+    /// Appends a download chunk to its partial file. Returns the final path once
+    /// the last chunk has arrived and the checksum matches.
+    async fn store_chunk(&self, msg: &ServerMessage) -> Result<Option<PathBuf>, Error> {
+        let transfer = match &msg.transfer {
+            Some(transfer) => transfer,
+            None => return Ok(None),
+        };
+        let partial = self.partial_path(&transfer.id);
+        if file_len(&partial).await != transfer.offset {
+            // a stale chunk from an earlier request
+            return Ok(None);
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&partial)
+            .await?;
+        file.write_all(&msg.content).await?;
+        file.flush().await?;
+        if !transfer.is_last(msg.content.len() as u64) {
+            return Ok(None);
+        }
+
+        let hash = sha256_file(&partial).await?;
+        if transfer.hash.as_ref() != Some(&hash) {
+            tokio::fs::remove_file(&partial).await?;
+            return Err(Error::ChecksumMismatch);
+        }
+        let filename = transfer.filename.as_deref().unwrap_or_default();
+        let (path, _) = self.create(&msg.from, filename).await?;
+        tokio::fs::rename(&partial, &path).await?;
+        Ok(Some(path))
+    }
+}
+
+/// How many times an upload or download is repeated after a checksum mismatch.
+const MAX_RETRIES: u8 = 3;
+
+type Waiters = Arc<Mutex<HashMap<String, oneshot::Sender<Transfer>>>>;
+
+/// Public keys of other users, by username.
+type Directory = Arc<Mutex<HashMap<String, Lookup>>>;
+
+enum Lookup {
+    /// Senders waiting for the server to answer a `KeyRequest`.
+    Waiting(Vec<oneshot::Sender<String>>),
+    Found(String),
+    Missing,
+}
+
+/// Reads the key pair from `path`, generating and storing a new one if there is none.
+async fn load_keys(path: &Path) -> Result<KeyPair, Error> {
+    match tokio::fs::read(path).await {
+        Ok(secret) => match secret.try_into() {
+            Ok(secret) => Ok(KeyPair::from_bytes(secret)),
+            Err(_) => Err(tokio::io::Error::from(ErrorKind::InvalidData).into()),
+        },
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let keys = KeyPair::generate();
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            options.mode(0o600);
+            let mut file = options.open(path).await?;
+            file.write_all(&keys.to_bytes()).await?;
+            file.flush().await?;
+            Ok(keys)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Reads the pinned public keys from `path`, there are none until it is written first.
+async fn load_pins(path: &Path) -> Result<HashMap<String, String>, Error> {
+    match tokio::fs::read(path).await {
+        Ok(json) => serde_json::from_slice(&json)
+            .map_err(|_| tokio::io::Error::from(ErrorKind::InvalidData).into()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Pins `key` for `user` in `pins` and writes them to `path`, unless another key is pinned already.
+async fn pin_key(
+    pins: &mut HashMap<String, String>,
+    path: &Path,
+    user: &str,
+    key: &str,
+) -> Result<(), Error> {
+    match pins.get(user) {
+        Some(pinned) if pinned == key => Ok(()),
+        Some(_) => Err(Error::KeyChanged(user.to_string())),
+        None => {
+            pins.insert(user.to_string(), key.to_string());
+            let json = serde_json::to_vec_pretty(&*pins).unwrap();
+            tokio::fs::write(path, json).await?;
+            Ok(())
+        }
+    }
+}
+
+/// Uploads not yet seen coming back from the server, by content hash, with the number of retries.
+type Pending = Arc<Mutex<HashMap<String, (ClientMessage, u8)>>>;
+
+/// Id of an upload, stable across reconnects so the server can find its partial file.
+fn upload_id(uname: &str, filename: &str, hash: &str) -> String {
+    let mut hasher = Sha256::new();
+    for part in [uname, filename, hash] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+async fn send_upload(
+    frames: &Sender<Frame>,
+    upload: Upload,
+    uname: &str,
+    waiters: &Waiters,
+    pending: &Pending,
+) -> Result<(), Error> {
+    let path = upload.path.as_path();
+    let filename = path
+        .file_name()
+        .map(|f| f.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let hash = sha256_file(path).await?;
+    pending
+        .lock()
+        .await
+        .entry(hash.clone())
+        .or_insert_with(|| (ClientMessage::Upload(upload.clone()), 0));
+    let mut file = File::open(path).await?;
+    let size = file.metadata().await?.len();
+    let id = upload_id(uname, &filename, &hash);
+
+    // ask the server how much of the file it already has
+    let (tx, rx) = oneshot::channel();
+    waiters.lock().await.insert(id.clone(), tx);
+    let status = Transfer {
+        id: id.clone(),
+        offset: 0,
+        size,
+        filename: Some(filename.clone()),
+        hash: None,
+        image: upload.image,
+        voice: upload.voice,
+    };
+    let header = serde_json::to_vec(&status).unwrap();
+    send_frame(frames, MessageType::TransferStatus, header, Vec::new()).await?;
+    let mut offset = match rx.await {
+        Ok(status) => status.offset.min(size),
+        Err(_) => {
+            pending.lock().await.remove(&hash);
+            return Err(Error::UploadRefused);
+        }
+    };
+
+    file.seek(SeekFrom::Start(offset)).await?;
+    loop {
+        let len = (size - offset).min(CHUNK_SIZE as u64) as usize;
+        let mut buf = vec![0; len];
+        file.read_exact(&mut buf).await?;
+        let chunk = Transfer {
+            offset,
+            hash: Some(hash.clone()).filter(|_| offset + len as u64 >= size),
+            ..status.clone()
+        };
+        let header = serde_json::to_vec(&chunk).unwrap();
+        send_frame(frames, MessageType::Chunk, header, buf).await?;
+        offset += len as u64;
+        if offset >= size {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// A notice that did not come from the server, shown like a `ServerNotice`.
+fn local_notice(text: String) -> ServerMessage {
+    ServerMessage {
+        desc: Descriptor::from(MessageType::ServerNotice).with_content_len(text.len() as u64),
+        timestamp: Utc::now(),
+        from: String::new(),
+        filename: None,
+        image: None,
+        voice: None,
+        offer: None,
+        transfer: None,
+        direct: None,
+        to: None,
+        moderation: None,
+        action: false,
+        content: text.into_bytes(),
+        saved: None,
+    }
+}
+
+/// A message ready to be written: type, header and content.
+type Frame = (MessageType, Vec<u8>, Vec<u8>);
+
+async fn send_frame(
+    frames: &Sender<Frame>,
+    t: MessageType,
+    header: Vec<u8>,
+    content: Vec<u8>,
+) -> Result<(), Error> {
+    frames
+        .send((t, header, content))
+        .await
+        .map_err(|_| tokio::io::Error::from(ErrorKind::BrokenPipe).into())
+}
+
+async fn write_msg<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    t: MessageType,
+    header: &[u8],
+    content: &[u8],
+    compression: Compression,
+) -> tokio::io::Result<()> {
+    let compressed = compression.compress(content);
+    let (compression, content) = match &compressed {
+        Some(compressed) => (compression, compressed.as_slice()),
+        None => (Compression::None, content),
+    };
+    writer
+        .write_all(
+            Descriptor::from(t)
+                .with_header_len(header.len() as u16)
+                .with_compression(compression)
+                .with_content_len(content.len() as u64)
+                .as_bytes(),
+        )
+        .await?;
+    writer.write_all(header).await?;
+    writer.write_all(content).await?;
+    writer.flush().await
+}
+
+async fn file_len(path: &Path) -> u64 {
+    tokio::fs::metadata(path)
+        .await
+        .map(|m| m.len())
+        .unwrap_or(0)
+}
+
+/// Creates `stem` + `ext` in `dir`, or `stem (1)` + `ext` and so on if it exists, never
+/// replacing a file.
+async fn create_new(dir: &Path, stem: &str, ext: &str) -> tokio::io::Result<(PathBuf, File)> {
+    let mut n = 0;
+    loop {
+        let path = if n == 0 {
+            dir.join(format!("{}{}", stem, ext))
+        } else {
+            dir.join(format!("{} ({}){}", stem, n, ext))
+        };
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await
+        {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Reduces a peer-supplied name to a single safe path component.
+///
+/// Directories (with either separator), control characters and leading dots are
+/// stripped, so the result can't escape the directory it is joined to or be hidden.
+pub fn sanitize_filename(name: &str) -> String {
+    const MAX_LEN: usize = 255;
+    let base = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let mut clean: String = base
+        .chars()
+        .filter(|c| !c.is_control() && *c != ':')
+        .collect::<String>()
+        .trim()
+        .trim_start_matches('.')
+        .to_string();
+    while clean.len() > MAX_LEN {
+        clean.pop();
+    }
+    if clean.is_empty() {
+        "file".to_string()
+    } else {
+        clean
+    }
+}
+
+fn normalize_extension(ext: &str) -> String {
+    ext.trim_start_matches('.').to_lowercase()
 }
 
 async fn read_msg<'h, R: AsyncReadExt>(
     mut reader: Pin<&mut R>,
     header_buf: &'h mut Vec<u8>,
 ) -> Result<(Descriptor, ServerHeader<'h, 'h>, Vec<u8>), Error> {
-    let desc = Descriptor::read(Pin::new(&mut reader)).await?;
+    let mut desc = Descriptor::read(Pin::new(&mut reader)).await?;
+    if desc.compression != Compression::None && desc.content_len > compression::MAX_LEN as u64 {
+        return Err(tokio::io::Error::from(ErrorKind::InvalidData).into());
+    }
     let mut content = Vec::new();
     header_buf.resize(desc.header_len as usize, 0u8);
     content.resize(desc.content_len as usize, 0u8);
     reader.read_exact(header_buf).await?;
     reader.read_exact(&mut content).await?;
-    Ok((desc, serde_json::from_slice(header_buf).unwrap(), content))
+    if desc.compression != Compression::None {
+        content = desc.compression.decompress(&content)?;
+        desc = desc
+            .with_compression(Compression::None)
+            .with_content_len(content.len() as u64);
+    }
+    let header = if header_buf.is_empty() {
+        ServerHeader::default()
+    } else {
+        serde_json::from_slice(header_buf).unwrap()
+    };
+    Ok((desc, header, content))
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum ClientMessage {
+    Close,
     Utf8(String),
-    File(PathBuf),
+    Upload(Upload),
+    Download(Transfer),
+    Usage,
+    KeyRequest(String),
+    Moderate {
+        r#type: MessageType,
+        header: Vec<u8>,
+    },
+    Direct {
+        header: Vec<u8>,
+        content: Vec<u8>,
+    },
+}
+
+/// A file, image or voice note to upload in chunks.
+#[derive(Debug, Clone)]
+struct Upload {
+    path: PathBuf,
+    image: Option<ImageInfo>,
+    voice: Option<VoiceInfo>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_strips_directories() {
+        assert_eq!(sanitize_filename("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("..\\..\\boot.ini"), "boot.ini");
+        assert_eq!(sanitize_filename("/etc/shadow"), "shadow");
+        assert_eq!(sanitize_filename("C:\\Windows\\evil.exe"), "evil.exe");
+        assert_eq!(sanitize_filename("C:evil.exe"), "Cevil.exe");
+        assert_eq!(sanitize_filename("dir/"), "file");
+    }
+
+    #[test]
+    fn sanitize_strips_control_characters_and_leading_dots() {
+        assert_eq!(sanitize_filename("a\nb\r\tc\0.txt"), "abc.txt");
+        assert_eq!(sanitize_filename("\x1b[31mred.txt"), "[31mred.txt");
+        assert_eq!(sanitize_filename(".bashrc"), "bashrc");
+        assert_eq!(sanitize_filename("..."), "file");
+        assert_eq!(sanitize_filename("  . hidden "), " hidden");
+    }
+
+    #[test]
+    fn sanitize_falls_back_to_file() {
+        assert_eq!(sanitize_filename(""), "file");
+        assert_eq!(sanitize_filename("   "), "file");
+        assert_eq!(sanitize_filename(".."), "file");
+    }
+
+    #[test]
+    fn sanitize_caps_at_255_bytes_on_a_char_boundary() {
+        assert_eq!(sanitize_filename(&"a".repeat(300)).len(), 255);
+        // two bytes per char, 128 of them would be 256 bytes
+        let long = sanitize_filename(&"я".repeat(200));
+        assert_eq!(long, "я".repeat(127));
+        assert!(long.len() <= 255);
+    }
+
+    #[tokio::test]
+    async fn pins_refuse_a_changed_key() {
+        let path = std::env::temp_dir().join(format!("chat-pins-{}", std::process::id()));
+        let mut pins = load_pins(&path).await.unwrap();
+        assert!(pins.is_empty());
+        pin_key(&mut pins, &path, "alice", "k1").await.unwrap();
+        pin_key(&mut pins, &path, "alice", "k1").await.unwrap();
+        pin_key(&mut pins, &path, "bob", "k2").await.unwrap();
+        let changed = pin_key(&mut pins, &path, "alice", "k2").await;
+        assert!(matches!(changed, Err(Error::KeyChanged(user)) if user == "alice"));
+
+        // the first key is still the one trusted after a restart
+        let mut reloaded = load_pins(&path).await.unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert_eq!(reloaded, pins);
+        let changed = pin_key(&mut reloaded, &path, "alice", "k3").await;
+        assert!(matches!(changed, Err(Error::KeyChanged(_))));
+    }
+
+    #[tokio::test]
+    async fn create_new_numbers_collisions() {
+        let dir = std::env::temp_dir().join(format!("chat-create-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let (first, _) = create_new(&dir, "photo", ".png").await.unwrap();
+        let (second, _) = create_new(&dir, "photo", ".png").await.unwrap();
+        let (third, _) = create_new(&dir, "photo", ".png").await.unwrap();
+        let (other, _) = create_new(&dir, "notes", "").await.unwrap();
+        let (other2, _) = create_new(&dir, "notes", "").await.unwrap();
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+        assert_eq!(first, dir.join("photo.png"));
+        assert_eq!(second, dir.join("photo (1).png"));
+        assert_eq!(third, dir.join("photo (2).png"));
+        assert_eq!(other, dir.join("notes"));
+        assert_eq!(other2, dir.join("notes (1)"));
+    }
 }
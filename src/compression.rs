@@ -0,0 +1,81 @@
+use std::io::{self, Read, Write};
+
+use num_enum::FromPrimitive;
+use serde::{Deserialize, Serialize};
+
+/// Contents shorter than this are never compressed, it wouldn't pay off.
+pub const MIN_LEN: usize = 512;
+
+/// Contents longer than this are never compressed, so the receiver can keep a whole
+/// compressed message in memory. Decompressing to more than this is an error.
+pub const MAX_LEN: usize = 16 * 1024 * 1024;
+
+/// How the content of a message is compressed, stored in the 5th byte of the descriptor.
+#[repr(u8)]
+#[derive(FromPrimitive, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None = 0,
+    Zstd = 1,
+    Deflate = 2,
+
+    #[num_enum(default)]
+    #[serde(other)]
+    Unknown = 255,
+}
+
+impl Compression {
+    /// Every algorithm this side supports, the preferred one first.
+    pub const SUPPORTED: [Self; 2] = [Self::Zstd, Self::Deflate];
+
+    /// Picks the first of the `offered` algorithms that is also `supported`.
+    pub fn negotiate(offered: &[Self], supported: &[Self]) -> Self {
+        offered
+            .iter()
+            .copied()
+            .find(|c| *c != Self::Unknown && supported.contains(c))
+            .unwrap_or(Self::None)
+    }
+
+    /// Compresses `content` if it is worth it, returning `None` when it should be sent as is.
+    pub fn compress(self, content: &[u8]) -> Option<Vec<u8>> {
+        if content.len() < MIN_LEN || content.len() > MAX_LEN {
+            return None;
+        }
+        let compressed = match self {
+            Self::Zstd => zstd::bulk::compress(content, 3).ok()?,
+            Self::Deflate => {
+                let mut encoder =
+                    flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder.write_all(content).ok()?;
+                encoder.finish().ok()?
+            }
+            Self::None | Self::Unknown => return None,
+        };
+        (compressed.len() < content.len()).then_some(compressed)
+    }
+
+    /// Decompresses `content`, refusing to produce more than [`MAX_LEN`] bytes.
+    pub fn decompress(self, content: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let limit = MAX_LEN as u64 + 1;
+        match self {
+            Self::None => out.extend_from_slice(content),
+            Self::Zstd => {
+                zstd::stream::read::Decoder::new(content)?
+                    .take(limit)
+                    .read_to_end(&mut out)?;
+            }
+            Self::Deflate => {
+                flate2::read::DeflateDecoder::new(content)
+                    .take(limit)
+                    .read_to_end(&mut out)?;
+            }
+            Self::Unknown => return Err(io::ErrorKind::InvalidData.into()),
+        }
+        if out.len() > MAX_LEN {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        Ok(out)
+    }
+}
@@ -0,0 +1,224 @@
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Length of a key in bytes, both secret and public.
+pub const KEY_LEN: usize = 32;
+
+/// X25519 key pair used for end-to-end encrypted direct messages.
+///
+/// Both sides of a conversation derive the same symmetric key from their secret and
+/// the other's public key, messages are sealed with XChaCha20-Poly1305 under a random nonce.
+/// The names of the sender and the recipient are authenticated along with the message, so
+/// the server can't pass it off as coming from someone else.
+pub struct KeyPair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        Self::from_secret(StaticSecret::random_from_rng(OsRng))
+    }
+
+    pub fn from_bytes(secret: [u8; KEY_LEN]) -> Self {
+        Self::from_secret(StaticSecret::from(secret))
+    }
+
+    fn from_secret(secret: StaticSecret) -> Self {
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    /// The secret key, to be kept in the keyfile.
+    pub fn to_bytes(&self) -> [u8; KEY_LEN] {
+        self.secret.to_bytes()
+    }
+
+    pub fn public_hex(&self) -> String {
+        to_hex(self.public.as_bytes())
+    }
+
+    /// Encrypts `plaintext` sent by `from` to `to`, the owner of `their_key`, returning the
+    /// hex encoded nonce and the ciphertext.
+    pub fn seal(
+        &self,
+        their_key: &str,
+        from: &str,
+        to: &str,
+        plaintext: &[u8],
+    ) -> Option<(String, Vec<u8>)> {
+        let cipher = self.cipher(their_key)?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = associated_data(from, to);
+        let payload = Payload {
+            msg: plaintext,
+            aad: &aad,
+        };
+        let ciphertext = cipher.encrypt(&nonce, payload).ok()?;
+        Some((to_hex(&nonce), ciphertext))
+    }
+
+    /// Decrypts a message `from`, the owner of `their_key`, to `to`, `None` if it was
+    /// tampered with or the names don't match those it was sealed with.
+    pub fn open(
+        &self,
+        their_key: &str,
+        from: &str,
+        to: &str,
+        nonce: &str,
+        ciphertext: &[u8],
+    ) -> Option<Vec<u8>> {
+        let cipher = self.cipher(their_key)?;
+        let nonce = from_hex(nonce).filter(|n| n.len() == 24)?;
+        let aad = associated_data(from, to);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &aad,
+        };
+        cipher.decrypt(XNonce::from_slice(&nonce), payload).ok()
+    }
+
+    fn cipher(&self, their_key: &str) -> Option<XChaCha20Poly1305> {
+        let their: [u8; KEY_LEN] = from_hex(their_key)?.try_into().ok()?;
+        let shared = self.secret.diffie_hellman(&PublicKey::from(their));
+        if !shared.was_contributory() {
+            return None;
+        }
+        // hash the keys in a fixed order so both sides end up with the same key
+        let (a, b) = if self.public.as_bytes() < &their {
+            (*self.public.as_bytes(), their)
+        } else {
+            (their, *self.public.as_bytes())
+        };
+        let key = Sha256::new()
+            .chain_update(b"chat direct message\0")
+            .chain_update(shared.as_bytes())
+            .chain_update(a)
+            .chain_update(b)
+            .finalize();
+        Some(XChaCha20Poly1305::new(&key))
+    }
+}
+
+/// The sender and the recipient, length prefixed so that no two pairs of names look alike.
+fn associated_data(from: &str, to: &str) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(16 + from.len() + to.len());
+    for name in [from, to] {
+        aad.extend_from_slice(&(name.len() as u64).to_be_bytes());
+        aad.extend_from_slice(name.as_bytes());
+    }
+    aad
+}
+
+/// Checks that `key` is a hex encoded public key.
+pub fn is_public_key(key: &str) -> bool {
+    key.len() == KEY_LEN * 2 && key.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let alice = KeyPair::generate();
+        let bob = KeyPair::generate();
+        let (nonce, sealed) = alice
+            .seal(&bob.public_hex(), "alice", "bob", b"hello")
+            .unwrap();
+        assert_ne!(sealed, b"hello");
+        let opened = bob.open(&alice.public_hex(), "alice", "bob", &nonce, &sealed);
+        assert_eq!(opened.as_deref(), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn keys_survive_the_keyfile() {
+        let alice = KeyPair::generate();
+        let bob = KeyPair::generate();
+        let restored = KeyPair::from_bytes(bob.to_bytes());
+        assert_eq!(restored.public_hex(), bob.public_hex());
+        let (nonce, sealed) = alice.seal(&bob.public_hex(), "a", "b", b"x").unwrap();
+        assert!(restored
+            .open(&alice.public_hex(), "a", "b", &nonce, &sealed)
+            .is_some());
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let alice = KeyPair::generate();
+        let bob = KeyPair::generate();
+        let mallory = KeyPair::generate();
+        let (nonce, sealed) = alice
+            .seal(&bob.public_hex(), "alice", "bob", b"hello")
+            .unwrap();
+        let alice_key = alice.public_hex();
+
+        let mut flipped = sealed.clone();
+        flipped[0] ^= 1;
+        assert!(bob
+            .open(&alice_key, "alice", "bob", &nonce, &flipped)
+            .is_none());
+        assert!(bob
+            .open(
+                &alice_key,
+                "alice",
+                "bob",
+                &nonce,
+                &sealed[..sealed.len() - 1]
+            )
+            .is_none());
+
+        let mut other_nonce = nonce.clone().into_bytes();
+        other_nonce[0] = if other_nonce[0] == b'0' { b'1' } else { b'0' };
+        let other_nonce = String::from_utf8(other_nonce).unwrap();
+        assert!(bob
+            .open(&alice_key, "alice", "bob", &other_nonce, &sealed)
+            .is_none());
+        assert!(bob
+            .open(&alice_key, "alice", "bob", "00", &sealed)
+            .is_none());
+
+        // the server can't change who the message is from or whom it was for
+        assert!(bob
+            .open(&alice_key, "mallory", "bob", &nonce, &sealed)
+            .is_none());
+        assert!(bob
+            .open(&alice_key, "alice", "carol", &nonce, &sealed)
+            .is_none());
+        assert!(bob
+            .open(&alice_key, "alicebob", "", &nonce, &sealed)
+            .is_none());
+
+        // nor can anyone else read it
+        assert!(mallory
+            .open(&alice_key, "alice", "bob", &nonce, &sealed)
+            .is_none());
+        assert!(bob
+            .open(&mallory.public_hex(), "alice", "bob", &nonce, &sealed)
+            .is_none());
+    }
+
+    #[test]
+    fn rejects_bad_keys() {
+        let alice = KeyPair::generate();
+        assert!(alice.seal("not a key", "a", "b", b"x").is_none());
+        assert!(alice.seal(&"00".repeat(KEY_LEN), "a", "b", b"x").is_none());
+        assert!(is_public_key(&alice.public_hex()));
+        assert!(!is_public_key(&alice.public_hex()[1..]));
+    }
+}
@@ -3,13 +3,22 @@ use std::pin::Pin;
 use chrono::{DateTime, Utc};
 use num_enum::FromPrimitive;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tokio::io::{self, AsyncReadExt};
 
+use compression::Compression;
+use media::{ImageInfo, VoiceInfo};
+
 pub mod client;
+pub mod compression;
+pub mod crypto;
+pub mod markup;
+pub mod media;
 pub mod server;
 
 #[repr(u16)]
-#[derive(FromPrimitive, PartialEq, Eq, Clone, Copy, Debug)]
+#[derive(FromPrimitive, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
 pub enum MessageType {
     Login = 1,
     Logout = 2,
@@ -23,7 +32,41 @@ pub enum MessageType {
     File = 8,
     Voice = 9,
 
+    BadImage = 10,
+    BadVoice = 11,
+
+    FileOffer = 12,
+    FileAccept = 13,
+    NoSuchFile = 14,
+
+    Chunk = 15,
+    TransferStatus = 16,
+    Download = 17,
+    ChecksumMismatch = 18,
+
+    QuotaExceeded = 19,
+    Usage = 20,
+    Forbidden = 21,
+
+    PublicKey = 22,
+    KeyRequest = 23,
+    Direct = 24,
+    NoSuchUser = 25,
+    BadDirect = 26,
+
+    Kick = 27,
+    Ban = 28,
+    Mute = 29,
+    Unban = 30,
+    Unmute = 31,
+
+    ServerNotice = 32,
+
+    Link = 33,
+    Nick = 34,
+
     #[num_enum(default)]
+    #[serde(other)]
     Unknwown,
 }
 
@@ -32,6 +75,9 @@ pub enum MessageType {
 pub struct Descriptor {
     pub r#type: MessageType,
     pub header_len: u16,
+    /// How the content is compressed, `content_len` is the compressed length.
+    pub compression: Compression,
+    reserved: [u8; 3],
     pub content_len: u64,
 }
 
@@ -43,6 +89,186 @@ pub struct ServerHeader<'u, 'f> {
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub filename: Option<&'f str>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<ImageInfo>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub voice: Option<VoiceInfo>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offer: Option<FileOffer>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transfer: Option<Transfer>,
+
+    /// Set when the content doesn't follow the header but is sent in `Chunk`
+    /// messages with `transfer.id` equal to `stream.id`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<Transfer>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<PublicKey>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub direct: Option<Direct>,
+
+    /// The user a `NoSuchUser` reply is about.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub moderation: Option<Moderation>,
+
+    /// Servers a message was relayed through over links, the one it was sent on first.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub via: Vec<String>,
+
+    /// The text of a `Utf8` message describes what the sender does, sent with `/me`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub action: bool,
+}
+
+/// A file kept by the server until clients ask for it with `FileAccept`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileOffer {
+    pub id: String,
+    pub size: u64,
+    /// Hex encoded SHA-256 of the content.
+    pub hash: String,
+}
+
+/// Spool usage, the content of a `Usage` reply.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UsageReport {
+    pub total: u64,
+    pub global_quota: Option<u64>,
+    pub user_quota: Option<u64>,
+    /// Free space left on the spool disk.
+    pub available: u64,
+    /// Bytes used by each user, largest first.
+    pub users: Vec<(String, u64)>,
+}
+
+/// A user's hex encoded X25519 public key, published with `PublicKey` and
+/// returned by the server in reply to `KeyRequest`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PublicKey {
+    #[serde(default)]
+    pub user: String,
+    pub key: String,
+}
+
+/// Header of an end-to-end encrypted `Direct` message, the content is the ciphertext.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Direct {
+    pub to: String,
+    /// Hex encoded public key of the sender.
+    pub key: String,
+    /// Hex encoded AEAD nonce.
+    pub nonce: String,
+}
+
+/// Header of the admin-only `Kick`, `Ban`, `Mute`, `Unban` and `Unmute` messages.
+/// The server announces them to everyone with the same header.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Moderation {
+    pub user: String,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+
+    /// How long a `Mute` lasts, forever if not set.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_secs: Option<u64>,
+}
+
+/// Header of the `Link` message one server opens a link to another with, and of the reply.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LinkInfo {
+    /// Users of the server show up on the other one as `user@server`.
+    pub server: String,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+/// Content of the `Login` message.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct LoginInfo {
+    /// Compression algorithms the client supports.
+    #[serde(default)]
+    pub compression: Vec<Compression>,
+
+    /// Admins present the server's admin token, without it they are ordinary users.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_token: Option<String>,
+}
+
+impl LoginInfo {
+    /// Reads the content of a `Login` message, anything unreadable offers no compression.
+    pub fn from_slice(content: &[u8]) -> Self {
+        serde_json::from_slice(content).unwrap_or_default()
+    }
+}
+
+/// `from` of the messages the server sends on its own, like the message of the day
+/// and announcements. Nobody may log in with this name.
+pub const SYSTEM_USER: &str = "server";
+
+/// Size of the content of a single `Chunk` message.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Describes a chunked upload or download.
+///
+/// In a `Chunk` message `offset` is the position of the chunk within the file, in a
+/// `TransferStatus` reply it is the number of bytes the server already has.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct Transfer {
+    pub id: String,
+    pub offset: u64,
+    #[serde(default)]
+    pub size: u64,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+
+    /// Hex encoded SHA-256 of the whole file, carried by the final chunk.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+
+    /// Set for an image upload, which is relayed as an `Image` message once complete.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<ImageInfo>,
+
+    /// Set for a voice note upload, which is relayed as a `Voice` message once complete.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub voice: Option<VoiceInfo>,
+}
+
+impl Transfer {
+    pub fn is_last(&self, chunk_len: u64) -> bool {
+        self.offset + chunk_len >= self.size
+    }
 }
 
 impl<'u, 'f> Default for ServerHeader<'u, 'f> {
@@ -51,6 +277,17 @@ impl<'u, 'f> Default for ServerHeader<'u, 'f> {
             timestamp: Utc::now(),
             from: "",
             filename: None,
+            image: None,
+            voice: None,
+            offer: None,
+            transfer: None,
+            stream: None,
+            key: None,
+            direct: None,
+            to: None,
+            moderation: None,
+            via: Vec::new(),
+            action: false,
         }
     }
 }
@@ -77,6 +314,8 @@ impl From<MessageType> for Descriptor {
         Self {
             r#type: t,
             header_len: 0,
+            compression: Compression::None,
+            reserved: [0; 3],
             content_len: 0,
         }
     }
@@ -93,11 +332,17 @@ impl Descriptor {
         self
     }
 
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
     #[inline(always)]
     pub fn from_bytes(bytes: &[u8]) -> Self {
         assert_eq!(bytes.len(), std::mem::size_of::<Self>());
         let r#type = MessageType::from((bytes[0] as u16) | ((bytes[1] as u16) << 8));
         let header_len = (bytes[2] as u16) | ((bytes[3] as u16) << 8);
+        let compression = Compression::from(bytes[4]);
         let mut content_len = 0u64;
         // SAFETY: this is safe because `content_len` is never unaligned and `src` and `dst` are treated as bytes
         unsafe {
@@ -111,6 +356,8 @@ impl Descriptor {
         Self {
             r#type,
             header_len,
+            compression,
+            reserved: [0; 3],
             content_len,
         }
     }
@@ -131,3 +378,23 @@ impl Descriptor {
         Ok(Self::from_bytes(&buf[..]))
     }
 }
+
+/// Hex encoded SHA-256 of `bytes`.
+pub fn sha256(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Hex encoded SHA-256 of the file at `path`.
+pub(crate) async fn sha256_file(path: impl AsRef<std::path::Path>) -> io::Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
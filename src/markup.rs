@@ -0,0 +1,281 @@
+//! A small markup for text messages: `*bold*`, `_italic_`, `` `code` ``, code blocks between
+//! ```` ``` ```` lines and `@username` mentions. Whatever doesn't parse stays plain text, so
+//! clients that don't render it still show the message as it was written.
+
+const FENCE: &str = "```";
+
+/// A line of a message, or a code block of several lines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Block<'a> {
+    Line(Vec<Inline<'a>>),
+    /// Lines between fences, shown as they are. `lang` is what follows the opening fence.
+    Code {
+        lang: Option<&'a str>,
+        lines: Vec<&'a str>,
+    },
+}
+
+/// A piece of a line, without the markup characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inline<'a> {
+    Plain(&'a str),
+    Bold(&'a str),
+    Italic(&'a str),
+    Code(&'a str),
+    /// The username, without the `@`.
+    Mention(&'a str),
+}
+
+/// Splits `text` into lines and code blocks. A code block that is never closed runs to the end
+/// of the message.
+pub fn parse(text: &str) -> Vec<Block<'_>> {
+    let mut blocks = Vec::new();
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        let fenced = line.trim();
+        let lang = match fenced.strip_prefix(FENCE) {
+            Some(lang) => lang,
+            None => {
+                blocks.push(Block::Line(parse_line(line)));
+                continue;
+            }
+        };
+        // ```like this``` on a single line
+        if let Some(code) = lang.strip_suffix(FENCE) {
+            blocks.push(Block::Code {
+                lang: None,
+                lines: vec![code],
+            });
+            continue;
+        }
+        let lang = Some(lang.trim()).filter(|lang| !lang.is_empty());
+        let lines = lines.by_ref().take_while(|l| l.trim() != FENCE).collect();
+        blocks.push(Block::Code { lang, lines });
+    }
+    blocks
+}
+
+/// Users mentioned in `text`, mentions in code don't count.
+pub fn mentions(text: &str) -> Vec<&str> {
+    parse(text)
+        .into_iter()
+        .filter_map(|block| match block {
+            Block::Line(inlines) => Some(inlines),
+            Block::Code { .. } => None,
+        })
+        .flatten()
+        .filter_map(|inline| match inline {
+            Inline::Mention(user) => Some(user),
+            _ => None,
+        })
+        .collect()
+}
+
+fn parse_line(line: &str) -> Vec<Inline<'_>> {
+    let mut inlines = Vec::new();
+    // markup characters are ASCII, so stepping over bytes never splits them
+    let bytes = line.as_bytes();
+    let mut plain = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let found = match bytes[i] {
+            // code is closed by as many backticks as opened it, so ``a`b`` works
+            b'`' => {
+                let run = line[i..].len() - line[i..].trim_start_matches('`').len();
+                let ticks = &line[i..i + run];
+                let start = i + ticks.len();
+                line[start..].find(ticks).filter(|&len| len > 0).map(|len| {
+                    (
+                        Inline::Code(&line[start..start + len]),
+                        start + len + ticks.len(),
+                    )
+                })
+            }
+            b'*' if opens(line, i) => {
+                closing(line, i, b'*').map(|end| (Inline::Bold(&line[i + 1..end]), end + 1))
+            }
+            b'_' if opens(line, i) => {
+                closing(line, i, b'_').map(|end| (Inline::Italic(&line[i + 1..end]), end + 1))
+            }
+            b'@' if !follows_word(line, i) => {
+                mention(&line[i + 1..]).map(|user| (Inline::Mention(user), i + 1 + user.len()))
+            }
+            _ => None,
+        };
+        match found {
+            Some((inline, next)) => {
+                if plain < i {
+                    inlines.push(Inline::Plain(&line[plain..i]));
+                }
+                inlines.push(inline);
+                i = next;
+                plain = next;
+            }
+            None => i += 1,
+        }
+    }
+    if plain < line.len() {
+        inlines.push(Inline::Plain(&line[plain..]));
+    }
+    inlines
+}
+
+/// Whether the character before `i` is part of a word, like in `snake_case` or `a@b.c`.
+fn follows_word(line: &str, i: usize) -> bool {
+    line[..i]
+        .chars()
+        .next_back()
+        .is_some_and(char::is_alphanumeric)
+}
+
+/// Whether the `*` or `_` at `i` opens emphasis: it starts a word and text follows it.
+fn opens(line: &str, i: usize) -> bool {
+    !follows_word(line, i)
+        && line[i + 1..]
+            .chars()
+            .next()
+            .is_some_and(|c| !c.is_whitespace())
+}
+
+/// Where the emphasis opened at `i` ends: a `delim` right after text and not inside a word.
+fn closing(line: &str, i: usize, delim: u8) -> Option<usize> {
+    let bytes = line.as_bytes();
+    (i + 2..bytes.len()).find(|&j| {
+        bytes[j] == delim
+            && !bytes[j - 1].is_ascii_whitespace()
+            && !line[j + 1..]
+                .chars()
+                .next()
+                .is_some_and(char::is_alphanumeric)
+    })
+}
+
+/// The username at the start of `rest`. Users of linked servers are mentioned as `@user@server`.
+fn mention(rest: &str) -> Option<&str> {
+    let len = rest
+        .find(|c: char| !(c.is_alphanumeric() || "_-.@".contains(c)))
+        .unwrap_or(rest.len());
+    // punctuation ending a sentence isn't part of the name
+    let user = rest[..len].trim_end_matches(['.', '-', '@']);
+    user.starts_with(|c: char| c.is_alphanumeric() || c == '_')
+        .then_some(user)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(text: &str) -> Vec<Inline<'_>> {
+        match parse(text).as_slice() {
+            [Block::Line(inlines)] => inlines.clone(),
+            blocks => panic!("expected a single line, got {:?}", blocks),
+        }
+    }
+
+    #[test]
+    fn underscores_inside_words_are_plain() {
+        assert_eq!(line("snake_case_name"), [Inline::Plain("snake_case_name")]);
+        assert_eq!(
+            line("call my_func _now_"),
+            [Inline::Plain("call my_func "), Inline::Italic("now")]
+        );
+    }
+
+    #[test]
+    fn emails_are_not_mentions() {
+        assert_eq!(line("write to a@b.c"), [Inline::Plain("write to a@b.c")]);
+        assert_eq!(mentions("a@b.c"), Vec::<&str>::new());
+        assert_eq!(
+            line("ask @bob."),
+            [
+                Inline::Plain("ask "),
+                Inline::Mention("bob"),
+                Inline::Plain(".")
+            ]
+        );
+        assert_eq!(mentions("hi @alice@office2!"), ["alice@office2"]);
+    }
+
+    #[test]
+    fn unclosed_fence_runs_to_the_end() {
+        assert_eq!(
+            parse("look:\n```rust\nlet a = 1;\n*not bold*"),
+            [
+                Block::Line(vec![Inline::Plain("look:")]),
+                Block::Code {
+                    lang: Some("rust"),
+                    lines: vec!["let a = 1;", "*not bold*"],
+                },
+            ]
+        );
+        assert_eq!(mentions("```\n@bob"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn closed_fence_and_single_line_code() {
+        assert_eq!(
+            parse("```\ncode\n```\nafter"),
+            [
+                Block::Code {
+                    lang: None,
+                    lines: vec!["code"],
+                },
+                Block::Line(vec![Inline::Plain("after")]),
+            ]
+        );
+        assert_eq!(
+            parse("```one line```"),
+            [Block::Code {
+                lang: None,
+                lines: vec!["one line"],
+            }]
+        );
+    }
+
+    #[test]
+    fn multi_byte_text_next_to_delimiters() {
+        assert_eq!(
+            line("это *жирный* и _курсив_"),
+            [
+                Inline::Plain("это "),
+                Inline::Bold("жирный"),
+                Inline::Plain(" и "),
+                Inline::Italic("курсив"),
+            ]
+        );
+        assert_eq!(
+            line("«*ёж*»"),
+            [Inline::Plain("«"), Inline::Bold("ёж"), Inline::Plain("»")]
+        );
+        assert_eq!(line("ёж_ёлка_"), [Inline::Plain("ёж_ёлка_")]);
+        assert_eq!(line("`код`é"), [Inline::Code("код"), Inline::Plain("é")]);
+        assert_eq!(
+            line("привет @Вася!"),
+            [
+                Inline::Plain("привет "),
+                Inline::Mention("Вася"),
+                Inline::Plain("!")
+            ]
+        );
+    }
+
+    #[test]
+    fn code_is_closed_by_the_same_number_of_backticks() {
+        assert_eq!(line("``a`b``"), [Inline::Code("a`b")]);
+        assert_eq!(
+            line("run `ls *` now"),
+            [
+                Inline::Plain("run "),
+                Inline::Code("ls *"),
+                Inline::Plain(" now")
+            ]
+        );
+        assert_eq!(line("a ` lone tick"), [Inline::Plain("a ` lone tick")]);
+    }
+
+    #[test]
+    fn unclosed_emphasis_is_plain() {
+        assert_eq!(line("2 * 3 = 6"), [Inline::Plain("2 * 3 = 6")]);
+        assert_eq!(line("*open"), [Inline::Plain("*open")]);
+    }
+}
@@ -0,0 +1,168 @@
+use image::{imageops::FilterType, GenericImageView, ImageFormat};
+use serde::{Deserialize, Serialize};
+
+use crate::sha256;
+
+/// Images with any side larger than this are rejected by the server.
+pub const MAX_IMAGE_SIDE: u32 = 16 * 1024;
+
+/// Metadata sent alongside an `Image` message.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub mime: String,
+    pub width: u32,
+    pub height: u32,
+    /// Hex encoded SHA-256 of the content.
+    #[serde(default)]
+    pub hash: String,
+}
+
+/// Decoded thumbnail, row-major RGB pixels.
+#[derive(Debug, Clone)]
+pub struct Thumbnail {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[u8; 3]>,
+}
+
+fn mime_of(format: ImageFormat) -> Option<&'static str> {
+    match format {
+        ImageFormat::Png => Some("image/png"),
+        ImageFormat::Jpeg => Some("image/jpeg"),
+        ImageFormat::Gif => Some("image/gif"),
+        ImageFormat::Bmp => Some("image/bmp"),
+        _ => None,
+    }
+}
+
+impl ImageInfo {
+    /// Detects format and dimensions of the encoded image.
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        let format = image::guess_format(bytes).ok()?;
+        let mime = mime_of(format)?;
+        let (width, height) = image::load_from_memory_with_format(bytes, format)
+            .ok()?
+            .dimensions();
+        Some(Self {
+            mime: mime.into(),
+            width,
+            height,
+            hash: sha256(bytes),
+        })
+    }
+
+    /// Checks that the header describes an image the server is willing to relay
+    /// and that `magic` (the first bytes of the content) matches the declared mime.
+    pub fn validate(&self, magic: &[u8]) -> bool {
+        let format_ok =
+            image::guess_format(magic).ok().and_then(mime_of) == Some(self.mime.as_str());
+        format_ok
+            && self.width > 0
+            && self.height > 0
+            && self.width <= MAX_IMAGE_SIDE
+            && self.height <= MAX_IMAGE_SIDE
+    }
+}
+
+impl Thumbnail {
+    /// Decodes `bytes` and downscales it to fit in `max_width` x `max_height` pixels,
+    /// preserving the aspect ratio.
+    pub fn new(bytes: &[u8], max_width: u32, max_height: u32) -> Option<Self> {
+        let img = image::load_from_memory(bytes)
+            .ok()?
+            .resize(max_width, max_height, FilterType::Triangle)
+            .to_rgb8();
+        let (width, height) = img.dimensions();
+        Some(Self {
+            width,
+            height,
+            pixels: img.pixels().map(|p| p.0).collect(),
+        })
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> Option<[u8; 3]> {
+        if x < self.width && y < self.height {
+            Some(self.pixels[(y * self.width + x) as usize])
+        } else {
+            None
+        }
+    }
+}
+
+/// Voice notes longer than this are rejected by the server.
+pub const MAX_VOICE_DURATION_MS: u64 = 5 * 60 * 1000;
+
+/// Metadata sent alongside a `Voice` message. Voice notes are PCM WAV files.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct VoiceInfo {
+    pub mime: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub duration_ms: u64,
+    /// Hex encoded SHA-256 of the content.
+    #[serde(default)]
+    pub hash: String,
+}
+
+impl VoiceInfo {
+    pub const MIME: &'static str = "audio/wav";
+
+    /// Parses the RIFF/WAVE header of `bytes`.
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return None;
+        }
+        let u16_at = |i: usize| Some(u16::from_le_bytes(bytes.get(i..i + 2)?.try_into().ok()?));
+        let u32_at = |i: usize| Some(u32::from_le_bytes(bytes.get(i..i + 4)?.try_into().ok()?));
+
+        let mut fmt = None;
+        let mut pos = 12;
+        while pos + 8 <= bytes.len() {
+            let id = &bytes[pos..pos + 4];
+            let size = u32_at(pos + 4)? as usize;
+            let body = pos + 8;
+            match id {
+                b"fmt " => {
+                    // 1 is integer PCM, 3 is IEEE float
+                    let format = u16_at(body)?;
+                    if format != 1 && format != 3 {
+                        return None;
+                    }
+                    let channels = u16_at(body + 2)?;
+                    let sample_rate = u32_at(body + 4)?;
+                    let byte_rate = u32_at(body + 8)?;
+                    fmt = Some((channels, sample_rate, byte_rate));
+                }
+                b"data" => {
+                    let (channels, sample_rate, byte_rate) = fmt?;
+                    if byte_rate == 0 {
+                        return None;
+                    }
+                    return Some(Self {
+                        mime: Self::MIME.into(),
+                        sample_rate,
+                        channels,
+                        duration_ms: size as u64 * 1000 / byte_rate as u64,
+                        hash: sha256(bytes),
+                    });
+                }
+                _ => {}
+            }
+            // chunks are padded to an even size
+            pos = body + size + (size & 1);
+        }
+        None
+    }
+
+    /// Checks that the header describes a voice note the server is willing to relay
+    /// and that `magic` (the first bytes of the content) is a WAV header.
+    pub fn validate(&self, magic: &[u8]) -> bool {
+        magic.len() >= 12
+            && &magic[0..4] == b"RIFF"
+            && &magic[8..12] == b"WAVE"
+            && self.mime == Self::MIME
+            && (1..=2).contains(&self.channels)
+            && (8_000..=192_000).contains(&self.sample_rate)
+            && self.duration_ms <= MAX_VOICE_DURATION_MS
+    }
+}
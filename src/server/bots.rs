@@ -0,0 +1,408 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{channel, Receiver, Sender},
+    time::timeout,
+};
+use tracing::{debug, warn};
+
+use super::{json_lines, metrics::response, Content, Context, InternalMessage};
+use crate::{Descriptor, MessageType, ServerHeader};
+
+/// Largest body a bot may post.
+const MAX_BODY: usize = 64 * 1024;
+
+/// Longest request line and headers of a request, or status line of a webhook response.
+const MAX_HEAD: u64 = 8 * 1024;
+
+/// How long a bot may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a webhook may take to answer.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts waiting to be delivered to webhooks, messages are dropped while it is full.
+const WEBHOOK_QUEUE: usize = 256;
+
+/// The body of `POST /messages`.
+#[derive(Deserialize)]
+struct Post {
+    text: String,
+}
+
+/// Answers `POST /messages` from bots over plain HTTP, one request per connection.
+pub(super) async fn serve(
+    listener: TcpListener,
+    tx: Sender<InternalMessage>,
+    ctx: Arc<Context>,
+) -> io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let tx = tx.clone();
+        let ctx = Arc::clone(&ctx);
+        tokio::spawn(async move {
+            match timeout(REQUEST_TIMEOUT, handle(stream, tx, ctx)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!(%addr, error = %e, "bot request failed"),
+                Err(_) => debug!(%addr, "bot request timed out"),
+            }
+        });
+    }
+}
+
+async fn handle(
+    stream: TcpStream,
+    tx: Sender<InternalMessage>,
+    ctx: Arc<Context>,
+) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut head = (&mut reader).take(MAX_HEAD);
+    let mut request = String::new();
+    head.read_line(&mut request).await?;
+    let mut content_len = 0;
+    let mut token = None;
+    // false if the headers are cut short, by the client or by the limit
+    let mut complete = false;
+    loop {
+        let mut line = String::new();
+        if head.read_line(&mut line).await? == 0 {
+            break;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            complete = true;
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_len = value.parse().unwrap_or(usize::MAX);
+            } else if name.eq_ignore_ascii_case("authorization") {
+                token = value.strip_prefix("Bearer ").map(str::to_string);
+            }
+        }
+    }
+
+    let mut parts = request.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        _ if !complete => response("431 Request Header Fields Too Large", "text/plain", ""),
+        (Some("POST"), Some("/messages")) if content_len > MAX_BODY => {
+            response("413 Payload Too Large", "text/plain", "")
+        }
+        (Some("POST"), Some("/messages")) => {
+            let mut body = vec![0; content_len];
+            reader.read_exact(&mut body).await?;
+            post(token, &body, &tx, &ctx).await
+        }
+        (_, Some("/messages")) => response("405 Method Not Allowed", "text/plain", ""),
+        _ => response("404 Not Found", "text/plain", ""),
+    };
+    writer.write_all(response.as_bytes()).await?;
+    writer.shutdown().await
+}
+
+/// Sends the text a bot posted to everyone, with the bot as the sender.
+async fn post(
+    token: Option<String>,
+    body: &[u8],
+    tx: &Sender<InternalMessage>,
+    ctx: &Context,
+) -> String {
+    let name = match token.and_then(|token| ctx.bot(&token)) {
+        Some(name) => name,
+        None => return response("401 Unauthorized", "text/plain", ""),
+    };
+    let text = match serde_json::from_slice::<Post>(body) {
+        Ok(post) if !post.text.is_empty() => post.text,
+        _ => {
+            return response(
+                "400 Bad Request",
+                "text/plain",
+                "expected {\"text\": \"...\"}\n",
+            )
+        }
+    };
+    if ctx.mutes.lock().unwrap().is_muted(&name) {
+        return response("403 Forbidden", "text/plain", "the bot is muted\n");
+    }
+    debug!(bot = %name, "bot message");
+    let header = ServerHeader {
+        timestamp: Utc::now(),
+        from: &name,
+        ..Default::default()
+    };
+    let header = Arc::new(serde_json::to_vec(&header).unwrap());
+    tx.send(InternalMessage::Message {
+        desc: Descriptor::from(MessageType::Utf8)
+            .with_header_len(header.len() as u16)
+            .with_content_len(text.len() as u64),
+        header,
+        content: Content::vec(text.into_bytes()),
+    })
+    .await
+    .unwrap();
+    response("204 No Content", "text/plain", "")
+}
+
+/// Posts broadcast messages to the configured webhooks in the background, one at a time.
+pub(super) struct Webhooks {
+    queue: Sender<(String, Vec<u8>)>,
+}
+
+impl Webhooks {
+    pub(super) fn spawn() -> Self {
+        let (queue, rx) = channel(WEBHOOK_QUEUE);
+        tokio::spawn(deliver(rx));
+        Self { queue }
+    }
+
+    /// Queues `msg` for every webhook whose pattern matches it. The body is the message as
+    /// the JSON lines listener writes it.
+    pub(super) fn post(&self, ctx: &Context, msg: &InternalMessage) {
+        let (desc, header, content) = match msg {
+            InternalMessage::Message {
+                desc,
+                header,
+                content,
+            } => (desc, header, content),
+            _ => return,
+        };
+        let config = ctx.config();
+        if config.webhooks.is_empty() {
+            return;
+        }
+        // large contents stay on disk, they are never text worth posting
+        let content = match content {
+            Content::Vec(content, _) => content.as_slice(),
+            _ => &[],
+        };
+        let payload = json_lines::to_json(desc.r#type, header, content);
+        let text = payload.get("text").and_then(Value::as_str);
+        let body = serde_json::to_vec(&payload).unwrap();
+        for hook in &config.webhooks {
+            let matches = match &hook.pattern {
+                Some(pattern) => text.is_some_and(|text| pattern.is_match(text)),
+                None => true,
+            };
+            if !matches {
+                continue;
+            }
+            if self
+                .queue
+                .try_send((hook.url.clone(), body.clone()))
+                .is_err()
+            {
+                warn!(url = %hook.url, "webhooks are behind, dropped a message");
+            }
+        }
+    }
+}
+
+async fn deliver(mut rx: Receiver<(String, Vec<u8>)>) {
+    while let Some((url, body)) = rx.recv().await {
+        match timeout(WEBHOOK_TIMEOUT, send(&url, &body)).await {
+            Ok(Ok(status)) if status.starts_with('2') => debug!(%url, "webhook delivered"),
+            Ok(Ok(status)) => warn!(%url, %status, "webhook refused a message"),
+            Ok(Err(e)) => warn!(%url, error = %e, "webhook failed"),
+            Err(_) => warn!(%url, "webhook timed out"),
+        }
+    }
+}
+
+/// Posts `body` to a plain `http://` URL and returns the status code of the response.
+async fn send(url: &str, body: &[u8]) -> io::Result<String> {
+    let (host, path) = split_url(url).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "only http:// URLs are supported",
+        )
+    })?;
+    let has_port = !host.ends_with(']')
+        && host
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+    let mut stream = if has_port {
+        TcpStream::connect(host).await?
+    } else {
+        TcpStream::connect((host.trim_start_matches('[').trim_end_matches(']'), 80)).await?
+    };
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        body.len()
+    );
+    stream.write_all(request.as_bytes()).await?;
+    stream.write_all(body).await?;
+    let mut status = String::new();
+    BufReader::new(stream)
+        .take(MAX_HEAD)
+        .read_line(&mut status)
+        .await?;
+    Ok(status
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// Splits `http://host[:port]/path` into the host with the port and the path.
+fn split_url(url: &str) -> Option<(&str, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    Some(match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Mutex, RwLock};
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::server::{
+        moderation::BanList, storage::Storage, Bot, Commands, ServerConfig, Webhook,
+    };
+
+    fn context(config: ServerConfig) -> Arc<Context> {
+        let dir = std::env::temp_dir().join(format!("chat-bots-{}", std::process::id()));
+        Arc::new(Context {
+            storage: Arc::new(Storage::new(dir.join("spool"), None, None, 0)),
+            bans: Mutex::new(BanList::load(dir.join("bans.json")).unwrap()),
+            config: RwLock::new(config),
+            reload: None,
+            mutes: Mutex::default(),
+            metrics: Default::default(),
+            commands: Commands::empty(),
+        })
+    }
+
+    fn text(from: &str, text: &str) -> InternalMessage {
+        let header = ServerHeader {
+            from,
+            ..Default::default()
+        };
+        let header = Arc::new(serde_json::to_vec(&header).unwrap());
+        InternalMessage::Message {
+            desc: Descriptor::from(MessageType::Utf8)
+                .with_header_len(header.len() as u16)
+                .with_content_len(text.len() as u64),
+            header,
+            content: Content::vec(text.as_bytes().to_vec()),
+        }
+    }
+
+    /// Sends a raw HTTP request and returns the status code of the response.
+    async fn request(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.split_whitespace().nth(1).unwrap().to_string()
+    }
+
+    fn post_request(token: Option<&str>, body: &str) -> String {
+        let auth = token.map_or(String::new(), |t| {
+            format!("Authorization: Bearer {}\r\n", t)
+        });
+        format!(
+            "POST /messages HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}",
+            auth,
+            body.len(),
+            body
+        )
+    }
+
+    #[tokio::test]
+    async fn webhook_receives_message() {
+        let hook = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", hook.local_addr().unwrap());
+        let ctx = context(ServerConfig {
+            webhooks: vec![Webhook::new(url)],
+            ..Default::default()
+        });
+
+        Webhooks::spawn().post(&ctx, &text("alice", "hello"));
+
+        let (stream, _) = hook.accept().await.unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut content_len = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(len) = line.strip_prefix("Content-Length: ") {
+                content_len = len.parse().unwrap();
+            }
+        }
+        let mut body = vec![0; content_len];
+        reader.read_exact(&mut body).await.unwrap();
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["type"], "utf8");
+        assert_eq!(body["from"], "alice");
+        assert_eq!(body["text"], "hello");
+    }
+
+    #[tokio::test]
+    async fn bot_posts_need_a_token_and_text() {
+        let ctx = context(ServerConfig {
+            bots: vec![Bot {
+                name: "ci".into(),
+                token: "secret".into(),
+            }],
+            ..Default::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, mut rx) = channel(8);
+        tokio::spawn(serve(listener, tx, ctx));
+
+        let body = r#"{"text":"build passed"}"#;
+        assert_eq!(request(addr, &post_request(None, body)).await, "401");
+        assert_eq!(
+            request(addr, &post_request(Some("wrong"), body)).await,
+            "401"
+        );
+        let bad = post_request(Some("secret"), r#"{"txt":"x"}"#);
+        assert_eq!(request(addr, &bad).await, "400");
+        assert!(rx.try_recv().is_err());
+
+        assert_eq!(
+            request(addr, &post_request(Some("secret"), body)).await,
+            "204"
+        );
+        match rx.recv().await.unwrap() {
+            InternalMessage::Message {
+                desc,
+                header,
+                content,
+            } => {
+                assert_eq!(desc.r#type, MessageType::Utf8);
+                let header: ServerHeader = serde_json::from_slice(&header).unwrap();
+                assert_eq!(header.from, "ci");
+                match content {
+                    Content::Vec(content, _) => assert_eq!(content.as_slice(), b"build passed"),
+                    _ => panic!("text should be kept in memory"),
+                }
+            }
+            _ => panic!("expected a message"),
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use std::{collections::BTreeMap, fmt, future::Future, pin::Pin, sync::Arc};
+
+use chrono::Utc;
+use tokio::{
+    io,
+    sync::{mpsc::Sender, oneshot, watch},
+};
+use tracing::{info, Span};
+
+use super::{
+    is_valid_username, server_notice, Content, Context, ControlRequest, ControlResponse,
+    InternalMessage, UserInfo,
+};
+use crate::{Descriptor, MessageType, ServerHeader};
+
+pub type CommandFuture<'a> = Pin<Box<dyn Future<Output = io::Result<()>> + Send + 'a>>;
+
+/// A command users run by sending text like `/name args`. The text itself is never shown
+/// to anyone, the command replies with server notices.
+pub trait Command: Send + Sync {
+    /// What follows the slash.
+    fn name(&self) -> &str;
+
+    /// A line shown by `/help`, like `/me <text> - tells everyone what you do`.
+    fn help(&self) -> &str;
+
+    /// Runs the command, `args` is the rest of the line after the name.
+    fn execute<'a>(&'a self, cx: &'a CommandContext<'_>, args: &'a str) -> CommandFuture<'a>;
+}
+
+/// Commands users can run, by name. The default ones are `/help`, `/who`, `/me` and `/nick`.
+#[derive(Clone)]
+pub struct Commands {
+    map: BTreeMap<String, Arc<dyn Command>>,
+}
+
+impl Default for Commands {
+    fn default() -> Self {
+        Self::empty()
+            .with_command(Help)
+            .with_command(Who)
+            .with_command(Me)
+            .with_command(Nick)
+    }
+}
+
+impl fmt::Debug for Commands {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.map.keys()).finish()
+    }
+}
+
+impl Commands {
+    /// No commands at all, text starting with a slash is sent as is.
+    pub fn empty() -> Self {
+        Self {
+            map: BTreeMap::new(),
+        }
+    }
+
+    /// Adds `command`, replacing one with the same name.
+    pub fn with_command(mut self, command: impl Command + 'static) -> Self {
+        self.map
+            .insert(command.name().to_string(), Arc::new(command));
+        self
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+/// What a command can do on behalf of the user who ran it.
+pub struct CommandContext<'a> {
+    pub(super) name: &'a watch::Sender<Arc<String>>,
+    pub(super) admin: bool,
+    pub(super) conn: &'a Sender<InternalMessage>,
+    pub(super) server: &'a Sender<InternalMessage>,
+    pub(super) ctx: &'a Context,
+}
+
+impl CommandContext<'_> {
+    /// The user who ran the command.
+    pub fn user(&self) -> Arc<String> {
+        Arc::clone(&self.name.borrow())
+    }
+
+    /// Whether the user logged in as an admin, with the admin token.
+    pub fn is_admin(&self) -> bool {
+        self.admin
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.ctx.mutes.lock().unwrap().is_muted(&self.user())
+    }
+
+    /// Sends `text` to the user who ran the command only.
+    pub async fn reply(&self, text: impl Into<String>) -> io::Result<()> {
+        // the connection only goes away when the user does
+        let _ = self.conn.send(server_notice(text.into())).await;
+        Ok(())
+    }
+
+    /// Sends `text` to everyone as if the user wrote it, unless they are muted.
+    pub async fn say(&self, text: impl Into<String>) -> io::Result<()> {
+        self.send_text(text.into(), false).await
+    }
+
+    /// Sends `text` to everyone as a server notice.
+    pub async fn announce(&self, text: impl Into<String>) -> io::Result<()> {
+        let (resp, _) = oneshot::channel();
+        let request = ControlRequest::Announce { text: text.into() };
+        self.server
+            .send(InternalMessage::Control { request, resp })
+            .await
+            .unwrap();
+        Ok(())
+    }
+
+    /// Users logged in, sorted by name.
+    pub async fn users(&self) -> Vec<UserInfo> {
+        let (resp, recv) = oneshot::channel();
+        let request = ControlRequest::Users;
+        self.server
+            .send(InternalMessage::Control { request, resp })
+            .await
+            .unwrap();
+        match recv.await {
+            Ok(ControlResponse::Users { users }) => users,
+            _ => Vec::new(),
+        }
+    }
+
+    async fn send_text(&self, text: String, action: bool) -> io::Result<()> {
+        if self.is_muted() {
+            return self.reply("You are muted.").await;
+        }
+        let user = self.user();
+        let header = ServerHeader {
+            timestamp: Utc::now(),
+            from: &user,
+            action,
+            ..Default::default()
+        };
+        let header = Arc::new(serde_json::to_vec(&header).unwrap());
+        self.server
+            .send(InternalMessage::Message {
+                desc: Descriptor::from(MessageType::Utf8)
+                    .with_header_len(header.len() as u16)
+                    .with_content_len(text.len() as u64),
+                header,
+                content: Content::vec(text.into_bytes()),
+            })
+            .await
+            .unwrap();
+        Ok(())
+    }
+
+    /// Asks the server task to rename the user, returns `Nick` once it did.
+    async fn rename(&self, new: String) -> MessageType {
+        let (resp, recv) = oneshot::channel();
+        self.server
+            .send(InternalMessage::Rename {
+                old: self.user(),
+                new: Arc::new(new.clone()),
+                resp,
+            })
+            .await
+            .unwrap();
+        let reply = recv.await.unwrap_or(MessageType::UsernameExists);
+        if reply == MessageType::Nick {
+            let old = self.name.send_replace(Arc::new(new));
+            let new = self.user();
+            info!(%old, %new, "renamed");
+            Span::current().record("user", tracing::field::display(&new));
+        }
+        reply
+    }
+}
+
+/// Runs the command on a line of text without its leading slash.
+pub(super) async fn run(line: &str, cx: &CommandContext<'_>) -> io::Result<()> {
+    let (name, args) = match line.split_once(char::is_whitespace) {
+        Some((name, args)) => (name, args.trim()),
+        None => (line, ""),
+    };
+    let command = cx.ctx.commands.map.get(name).cloned();
+    match command {
+        Some(command) => command.execute(cx, args).await,
+        None => {
+            cx.reply(format!("No such command: /{}, see /help.", name))
+                .await
+        }
+    }
+}
+
+struct Help;
+
+impl Command for Help {
+    fn name(&self) -> &str {
+        "help"
+    }
+
+    fn help(&self) -> &str {
+        "/help - lists the commands"
+    }
+
+    fn execute<'a>(&'a self, cx: &'a CommandContext<'_>, _: &'a str) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let lines: Vec<_> = cx
+                .ctx
+                .commands
+                .map
+                .values()
+                .map(|c| c.help().to_string())
+                .collect();
+            cx.reply(lines.join("\n")).await
+        })
+    }
+}
+
+struct Who;
+
+impl Command for Who {
+    fn name(&self) -> &str {
+        "who"
+    }
+
+    fn help(&self) -> &str {
+        "/who - lists the users online"
+    }
+
+    fn execute<'a>(&'a self, cx: &'a CommandContext<'_>, _: &'a str) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let names: Vec<_> = cx
+                .users()
+                .await
+                .into_iter()
+                .map(|u| {
+                    if u.admin {
+                        format!("@{}", u.name)
+                    } else {
+                        u.name
+                    }
+                })
+                .collect();
+            cx.reply(format!("Online: {}.", names.join(", "))).await
+        })
+    }
+}
+
+struct Me;
+
+impl Command for Me {
+    fn name(&self) -> &str {
+        "me"
+    }
+
+    fn help(&self) -> &str {
+        "/me <text> - tells everyone what you do"
+    }
+
+    fn execute<'a>(&'a self, cx: &'a CommandContext<'_>, args: &'a str) -> CommandFuture<'a> {
+        Box::pin(async move {
+            if args.is_empty() {
+                return cx.reply("Usage: /me <text>").await;
+            }
+            cx.send_text(args.to_string(), true).await
+        })
+    }
+}
+
+struct Nick;
+
+impl Command for Nick {
+    fn name(&self) -> &str {
+        "nick"
+    }
+
+    fn help(&self) -> &str {
+        "/nick <name> - changes your username"
+    }
+
+    fn execute<'a>(&'a self, cx: &'a CommandContext<'_>, args: &'a str) -> CommandFuture<'a> {
+        Box::pin(async move {
+            if args.is_empty() || args.contains(char::is_whitespace) {
+                return cx.reply("Usage: /nick <name>").await;
+            }
+            // mutes and bans stay with the old name
+            if cx.is_muted() {
+                return cx.reply("You are muted.").await;
+            }
+            let banned = cx.ctx.bans.lock().unwrap().is_banned(args);
+            if banned || !is_valid_username(cx.ctx, args) {
+                return cx
+                    .reply(format!("{} can't be used as a username.", args))
+                    .await;
+            }
+            match cx.rename(args.to_string()).await {
+                MessageType::Nick => Ok(()),
+                MessageType::Forbidden => {
+                    cx.reply("Wait until your uploads and offered files are gone.")
+                        .await
+                }
+                _ => cx.reply(format!("{} is already taken.", args)).await,
+            }
+        })
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    env::temp_dir,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
+
+use crate::compression::Compression;
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Where uploads are kept while they are relayed or offered.
+    pub spool_dir: PathBuf,
+    /// Maximum bytes a single user may occupy in the spool.
+    pub user_quota: Option<u64>,
+    /// Maximum bytes all users together may occupy in the spool.
+    pub global_quota: Option<u64>,
+    /// Uploads are refused when the spool disk would have less free space than this.
+    pub min_free_space: u64,
+    /// Largest file, image or voice message accepted, in bytes.
+    pub max_upload_size: Option<u64>,
+    /// How long offered files and idle partial uploads are kept.
+    pub offer_ttl: Duration,
+    /// How many recent text messages are sent to users when they join, at most 127.
+    pub history_depth: usize,
+    /// Users allowed to see the usage report and to kick, ban and mute others.
+    pub admins: Vec<String>,
+    /// Token admins must present at login, nobody is an admin in the chat if not set.
+    pub admin_token: Option<String>,
+    /// Message of the day.
+    pub motd: Option<String>,
+    /// Where banned users and addresses are kept.
+    pub ban_file: PathBuf,
+    /// Unix socket for `chatctl`, disabled if not set.
+    pub control_socket: Option<PathBuf>,
+    /// Address of the HTTP `/metrics` endpoint, disabled if not set.
+    pub metrics_address: Option<String>,
+    /// Address to accept WebSocket clients on, disabled if not set.
+    pub websocket_address: Option<String>,
+    /// Address to accept clients speaking JSON lines on, disabled if not set.
+    pub json_address: Option<String>,
+    /// Address to accept IRC clients on, disabled if not set.
+    pub irc_address: Option<String>,
+    /// Name of this server on links, its users show up on linked servers as `user@name`.
+    pub server_name: String,
+    /// Address to accept links from other servers on, disabled if not set.
+    pub link_address: Option<String>,
+    /// Servers to keep a link to.
+    pub links: Vec<String>,
+    /// Secret a server must present to link to this one, and that is presented to `links`.
+    /// Links are refused without it.
+    pub link_secret: Option<String>,
+    /// Address of the HTTP endpoint bots post messages to, disabled if not set.
+    pub bot_address: Option<String>,
+    /// Bots allowed to post, their names can't be used to log in.
+    pub bots: Vec<Bot>,
+    /// Where broadcast messages are posted to.
+    pub webhooks: Vec<Webhook>,
+    /// Compression algorithms offered to clients, the preferred one first.
+    pub compression: Vec<Compression>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            spool_dir: temp_dir().join("chat-spool"),
+            user_quota: None,
+            global_quota: None,
+            min_free_space: 64 * 1024 * 1024,
+            max_upload_size: None,
+            offer_ttl: Duration::from_secs(24 * 60 * 60),
+            history_depth: 0,
+            admins: Vec::new(),
+            admin_token: None,
+            motd: None,
+            ban_file: PathBuf::from("bans.json"),
+            control_socket: None,
+            metrics_address: None,
+            websocket_address: None,
+            json_address: None,
+            irc_address: None,
+            server_name: "chat".to_string(),
+            link_address: None,
+            links: Vec::new(),
+            link_secret: None,
+            bot_address: None,
+            bots: Vec::new(),
+            webhooks: Vec::new(),
+            compression: Compression::SUPPORTED.to_vec(),
+        }
+    }
+}
+
+/// A bot posting with `Authorization: Bearer <token>` shows up as `name`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Bot {
+    pub name: String,
+    pub token: String,
+}
+
+/// Every broadcast message is posted to `url` as JSON, only text matching `pattern` if set.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    /// A plain `http://` URL.
+    pub url: String,
+    #[serde(default, deserialize_with = "deserialize_pattern")]
+    pub pattern: Option<Regex>,
+}
+
+impl Webhook {
+    pub fn new(url: String) -> Self {
+        Self { url, pattern: None }
+    }
+}
+
+fn deserialize_pattern<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Regex>, D::Error> {
+    let pattern = String::deserialize(d)?;
+    Regex::new(&pattern)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Builds the configuration again when the server is asked to reload it,
+/// on `SIGHUP` or by `chatctl reload`.
+pub type Reload = Arc<dyn Fn() -> Result<ServerConfig, ConfigError> + Send + Sync>;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    Toml(#[from] toml::de::Error),
+}
+
+/// The TOML configuration file, every setting is optional.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Addresses to listen on, only read by the server binary.
+    pub bind: Vec<String>,
+    pub spool_dir: Option<PathBuf>,
+    pub user_quota: Option<u64>,
+    pub global_quota: Option<u64>,
+    pub min_free_space: Option<u64>,
+    pub max_upload_size: Option<u64>,
+    /// In seconds.
+    pub offer_ttl: Option<u64>,
+    pub history_depth: Option<usize>,
+    pub admins: Option<Vec<String>>,
+    pub admin_token: Option<String>,
+    pub motd: Option<String>,
+    pub ban_file: Option<PathBuf>,
+    pub control_socket: Option<PathBuf>,
+    pub metrics_address: Option<String>,
+    pub websocket_address: Option<String>,
+    pub json_address: Option<String>,
+    pub irc_address: Option<String>,
+    pub server_name: Option<String>,
+    pub link_address: Option<String>,
+    pub links: Option<Vec<String>>,
+    pub link_secret: Option<String>,
+    pub bot_address: Option<String>,
+    pub bots: Option<Vec<Bot>>,
+    pub webhooks: Option<Vec<Webhook>>,
+    pub compression: Option<Vec<Compression>>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// Overrides the settings of `config` that are present in the file.
+    pub fn apply(self, config: &mut ServerConfig) {
+        if let Some(spool_dir) = self.spool_dir {
+            config.spool_dir = spool_dir;
+        }
+        if self.user_quota.is_some() {
+            config.user_quota = self.user_quota;
+        }
+        if self.global_quota.is_some() {
+            config.global_quota = self.global_quota;
+        }
+        if let Some(min_free_space) = self.min_free_space {
+            config.min_free_space = min_free_space;
+        }
+        if self.max_upload_size.is_some() {
+            config.max_upload_size = self.max_upload_size;
+        }
+        if let Some(offer_ttl) = self.offer_ttl {
+            config.offer_ttl = Duration::from_secs(offer_ttl);
+        }
+        if let Some(history_depth) = self.history_depth {
+            config.history_depth = history_depth;
+        }
+        if let Some(admins) = self.admins {
+            config.admins = admins;
+        }
+        if self.admin_token.is_some() {
+            config.admin_token = self.admin_token;
+        }
+        if self.motd.is_some() {
+            config.motd = self.motd;
+        }
+        if let Some(ban_file) = self.ban_file {
+            config.ban_file = ban_file;
+        }
+        if self.control_socket.is_some() {
+            config.control_socket = self.control_socket;
+        }
+        if self.metrics_address.is_some() {
+            config.metrics_address = self.metrics_address;
+        }
+        if self.websocket_address.is_some() {
+            config.websocket_address = self.websocket_address;
+        }
+        if self.json_address.is_some() {
+            config.json_address = self.json_address;
+        }
+        if self.irc_address.is_some() {
+            config.irc_address = self.irc_address;
+        }
+        if let Some(server_name) = self.server_name {
+            config.server_name = server_name;
+        }
+        if self.link_address.is_some() {
+            config.link_address = self.link_address;
+        }
+        if let Some(links) = self.links {
+            config.links = links;
+        }
+        if self.link_secret.is_some() {
+            config.link_secret = self.link_secret;
+        }
+        if self.bot_address.is_some() {
+            config.bot_address = self.bot_address;
+        }
+        if let Some(bots) = self.bots {
+            config.bots = bots;
+        }
+        if let Some(webhooks) = self.webhooks {
+            config.webhooks = webhooks;
+        }
+        if let Some(compression) = self.compression {
+            config.compression = compression;
+        }
+    }
+}
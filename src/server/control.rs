@@ -0,0 +1,154 @@
+use std::{
+    fs::Permissions,
+    net::SocketAddr,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::DirBuilder,
+    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc::Sender, oneshot},
+};
+
+use super::InternalMessage;
+use crate::UsageReport;
+
+/// A command sent to the control socket, one JSON object per line.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Users,
+    Kick {
+        user: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    /// Sends a `ServerNotice` to everyone.
+    Announce {
+        text: String,
+    },
+    Stats,
+    /// Reads the configuration and the ban list again.
+    Reload,
+}
+
+/// The answer to a [`ControlRequest`], one JSON object per line.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Ok,
+    Users { users: Vec<UserInfo> },
+    Stats(Stats),
+    Error { message: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UserInfo {
+    pub name: String,
+    pub addr: SocketAddr,
+    pub admin: bool,
+    pub muted: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    pub users: usize,
+    pub offers: usize,
+    /// Messages broadcast to everyone since the start.
+    pub relayed: u64,
+    pub uptime_secs: u64,
+    pub storage: UsageReport,
+}
+
+/// Accepts operators on the Unix socket at `path`, replacing a stale socket file.
+pub(super) async fn serve(path: PathBuf, tx: Sender<InternalMessage>) -> io::Result<()> {
+    let listener = bind(&path).await?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let tx = tx.clone();
+        tokio::spawn(async move { handle(stream, tx).await });
+    }
+}
+
+/// Binds the socket in a directory only the user running the server can enter, so nobody
+/// else can connect before its permissions are restricted, then moves it to `path`.
+/// Anything at `path` other than a socket is left alone, a mistyped path must not cost a file.
+async fn bind(path: &Path) -> io::Result<UnixListener> {
+    match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let dir = path.with_extension(format!("tmp-{}", std::process::id()));
+    let _ = tokio::fs::remove_dir_all(&dir).await;
+    DirBuilder::new().mode(0o700).create(&dir).await?;
+    let bound = async {
+        let tmp = dir.join("socket");
+        let listener = UnixListener::bind(&tmp)?;
+        // only the user running the server may control it
+        tokio::fs::set_permissions(&tmp, Permissions::from_mode(0o600)).await?;
+        // replaces a stale socket file
+        tokio::fs::rename(&tmp, path).await?;
+        io::Result::Ok(listener)
+    }
+    .await;
+    let _ = tokio::fs::remove_dir_all(&dir).await;
+    bound
+}
+
+async fn handle(stream: UnixStream, tx: Sender<InternalMessage>) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => {
+                let (resp, recv) = oneshot::channel();
+                let _ = tx.send(InternalMessage::Control { request, resp }).await;
+                recv.await.unwrap_or_else(|_| ControlResponse::Error {
+                    message: "server is shutting down".into(),
+                })
+            }
+            Err(e) => ControlResponse::Error {
+                message: e.to_string(),
+            },
+        };
+        let mut json = serde_json::to_vec(&response).unwrap();
+        json.push(b'\n');
+        writer.write_all(&json).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn bind_replaces_only_sockets() {
+        let dir = std::env::temp_dir().join(format!("chat-control-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+
+        let file = dir.join("notes.txt");
+        tokio::fs::write(&file, "keep me").await.unwrap();
+        let refused = bind(&file).await;
+        let kept = tokio::fs::read_to_string(&file).await.unwrap();
+
+        // a socket left behind by an earlier run is replaced
+        let socket = dir.join("control.sock");
+        drop(UnixListener::bind(&socket).unwrap());
+        let rebound = bind(&socket).await;
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+        assert_eq!(refused.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(kept, "keep me");
+        assert!(rebound.is_ok());
+    }
+}
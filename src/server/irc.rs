@@ -0,0 +1,516 @@
+use std::{
+    borrow::Cow,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use tokio::{
+    io::{
+        self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream, ReadHalf,
+        WriteHalf,
+    },
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    sync::{self, mpsc::Sender, oneshot},
+};
+
+use super::{
+    handle_connection, read_piped_msg, Context, ControlRequest, ControlResponse, InternalMessage,
+    BUF_SIZE,
+};
+use crate::{Descriptor, LoginInfo, MessageType, ServerHeader, SYSTEM_USER};
+
+/// Name of the server in replies and in the host part of user masks.
+const SERVER_NAME: &str = "chat";
+
+/// The room, IRC clients join it when they register.
+const CHANNEL: &str = "#chat";
+
+/// Longest line a client may send, with the line break, as IRC allows.
+const MAX_LINE: u64 = 512;
+
+/// Accepts IRC clients. They log in as regular users once they've sent `NICK` and `USER`,
+/// the room is the `#chat` channel.
+pub(super) async fn serve(
+    listener: TcpListener,
+    tx: Sender<InternalMessage>,
+    ctx: Arc<Context>,
+) -> io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let tx = tx.clone();
+        let ctx = Arc::clone(&ctx);
+        tokio::spawn(async move { handle(stream, addr, tx, ctx).await });
+    }
+}
+
+async fn handle(
+    stream: TcpStream,
+    addr: SocketAddr,
+    tx: Sender<InternalMessage>,
+    ctx: Arc<Context>,
+) -> io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let session = Session {
+        motd: ctx.config().motd.is_some(),
+        output: Arc::new(sync::Mutex::new(writer)),
+        state: Arc::default(),
+        tx: tx.clone(),
+    };
+    // the connection is handled like a TCP one, on the other end of a pipe
+    let (ours, theirs) = io::duplex(BUF_SIZE);
+    let (our_reader, our_writer) = io::split(ours);
+    tokio::spawn(commands_to_pipe(reader, our_writer, session.clone()));
+    tokio::spawn(pipe_to_commands(our_reader, session));
+    let (reader, writer) = io::split(theirs);
+    handle_connection(reader, writer, addr, tx, ctx).await
+}
+
+#[derive(Debug, Default)]
+struct State {
+    nick: Option<String>,
+    /// `USER` was sent.
+    user: bool,
+    /// The login was sent and the server hasn't answered yet.
+    pending: bool,
+    /// The server accepted the nick as username.
+    registered: bool,
+    joined: bool,
+}
+
+#[derive(Clone)]
+struct Session {
+    /// The message of the day follows the login as a notice.
+    motd: bool,
+    output: Arc<sync::Mutex<OwnedWriteHalf>>,
+    state: Arc<Mutex<State>>,
+    tx: Sender<InternalMessage>,
+}
+
+impl Session {
+    /// Sends `line`, any line breaks in the values it was built from become spaces.
+    async fn send(&self, line: &str) -> io::Result<()> {
+        let line = one_line(line);
+        let mut output = self.output.lock().await;
+        output.write_all(line.as_bytes()).await?;
+        output.write_all(b"\r\n").await
+    }
+
+    /// Sends a numeric reply addressed to the client.
+    async fn reply(&self, code: &str, params: &str) -> io::Result<()> {
+        let nick = self.nick();
+        self.send(&format!(":{} {} {} {}", SERVER_NAME, code, nick, params))
+            .await
+    }
+
+    fn nick(&self) -> String {
+        let state = self.state.lock().unwrap();
+        state.nick.clone().unwrap_or_else(|| "*".into())
+    }
+
+    async fn join(&self) -> io::Result<()> {
+        self.state.lock().unwrap().joined = true;
+        self.send(&format!(":{} JOIN {}", prefix(&self.nick()), CHANNEL))
+            .await?;
+        self.names().await
+    }
+
+    async fn names(&self) -> io::Result<()> {
+        let (resp, recv) = oneshot::channel();
+        let request = ControlRequest::Users;
+        let _ = self
+            .tx
+            .send(InternalMessage::Control { request, resp })
+            .await;
+        if let Ok(ControlResponse::Users { users }) = recv.await {
+            let names: Vec<_> = users
+                .iter()
+                .map(|u| {
+                    if u.admin {
+                        format!("@{}", u.name)
+                    } else {
+                        u.name.clone()
+                    }
+                })
+                .collect();
+            self.reply("353", &format!("= {} :{}", CHANNEL, names.join(" ")))
+                .await?;
+        }
+        self.reply("366", &format!("{} :End of /NAMES list.", CHANNEL))
+            .await
+    }
+}
+
+/// `nick!nick@chat` for users, the server name for the server itself. Users of linked
+/// servers are `nick|server!nick@server`, since nicks can't contain `@`.
+fn prefix(user: &str) -> String {
+    if user == SYSTEM_USER {
+        SERVER_NAME.to_string()
+    } else if let Some((nick, server)) = user.split_once('@') {
+        format!("{0}|{1}!{0}@{1}", nick, server)
+    } else {
+        format!("{0}!{0}@{1}", user, SERVER_NAME)
+    }
+}
+
+/// Replaces the characters that would end an IRC line early, nicks, filenames and
+/// reasons come from users and may contain them.
+fn one_line(line: &str) -> Cow<'_, str> {
+    if line.contains(['\r', '\n', '\0']) {
+        line.replace(['\r', '\n', '\0'], " ").into()
+    } else {
+        line.into()
+    }
+}
+
+/// Splits a line into the upper-cased command and its parameters, the prefix is ignored.
+fn parse(line: &str) -> (String, Vec<String>) {
+    let mut rest = line.trim_end_matches('\r');
+    if rest.starts_with(':') {
+        rest = rest.split_once(' ').map_or("", |(_, rest)| rest);
+    }
+    let (rest, trailing) = match rest.split_once(" :") {
+        Some((rest, trailing)) => (rest, Some(trailing)),
+        None => (rest, None),
+    };
+    let mut words = rest.split_whitespace();
+    let command = words.next().unwrap_or_default().to_ascii_uppercase();
+    let mut params: Vec<_> = words.map(String::from).collect();
+    params.extend(trailing.map(String::from));
+    (command, params)
+}
+
+fn message(t: MessageType, header: &[u8], content: &[u8]) -> Vec<u8> {
+    let mut msg = Descriptor::from(t)
+        .with_header_len(header.len() as u16)
+        .with_content_len(content.len() as u64)
+        .as_bytes()
+        .to_vec();
+    msg.extend_from_slice(header);
+    msg.extend_from_slice(content);
+    msg
+}
+
+fn login(nick: &str) -> Vec<u8> {
+    // offer no compression, contents are turned into lines of text
+    let info = serde_json::to_vec(&LoginInfo::default()).unwrap();
+    message(MessageType::Login, nick.as_bytes(), &info)
+}
+
+async fn commands_to_pipe(
+    reader: OwnedReadHalf,
+    mut pipe: WriteHalf<DuplexStream>,
+    session: Session,
+) -> io::Result<()> {
+    let result = read_commands(reader, &mut pipe, &session).await;
+    // the connection ends once the server reads to the end of the pipe
+    pipe.shutdown().await?;
+    result
+}
+
+async fn read_commands(
+    reader: OwnedReadHalf,
+    pipe: &mut WriteHalf<DuplexStream>,
+    session: &Session,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = (&mut reader)
+            .take(MAX_LINE + 1)
+            .read_line(&mut line)
+            .await?;
+        if read == 0 {
+            break;
+        }
+        if read as u64 > MAX_LINE {
+            // there is no telling where the next command starts, so the link is closed
+            session.reply("417", ":Input line was too long").await?;
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let (command, params) = parse(line.trim_end_matches('\n'));
+        let registered = session.state.lock().unwrap().registered;
+        match command.as_str() {
+            "" => {}
+            "PING" => {
+                let token = params.first().map_or("", String::as_str);
+                session
+                    .send(&format!(":{0} PONG {0} :{1}", SERVER_NAME, token))
+                    .await?;
+            }
+            "CAP" if params.first().is_some_and(|p| p == "LS") => {
+                session
+                    .send(&format!(":{} CAP * LS :", SERVER_NAME))
+                    .await?;
+            }
+            "CAP" | "PONG" => {}
+            "QUIT" => break,
+            "NICK" if registered => match params.first() {
+                // renamed by the `/nick` command, the server answers with `Nick`
+                Some(nick) => {
+                    let text = format!("/nick {}", nick);
+                    pipe.write_all(&message(MessageType::Utf8, &[], text.as_bytes()))
+                        .await?;
+                }
+                None => session.reply("431", ":No nickname given").await?,
+            },
+            "NICK" => match params.first() {
+                Some(nick) => {
+                    // ignored until the server answers the login sent with the last one
+                    let send = {
+                        let mut state = session.state.lock().unwrap();
+                        if state.pending {
+                            false
+                        } else {
+                            state.nick = Some(nick.clone());
+                            state.pending = state.user;
+                            state.user
+                        }
+                    };
+                    if send {
+                        pipe.write_all(&login(nick)).await?;
+                    }
+                }
+                None => session.reply("431", ":No nickname given").await?,
+            },
+            "USER" => {
+                let nick = {
+                    let mut state = session.state.lock().unwrap();
+                    let first = !state.user;
+                    state.user = true;
+                    let nick = state.nick.clone().filter(|_| first && !registered);
+                    state.pending |= nick.is_some();
+                    nick
+                };
+                if let Some(nick) = nick {
+                    pipe.write_all(&login(&nick)).await?;
+                }
+            }
+            _ if !registered => session.reply("451", ":You have not registered").await?,
+            "PRIVMSG" | "NOTICE" => match (params.first(), params.get(1)) {
+                (Some(target), Some(text)) if target == CHANNEL => {
+                    if session.state.lock().unwrap().joined {
+                        let text = match text
+                            .strip_prefix("\x01ACTION ")
+                            .map(|t| t.trim_end_matches('\x01'))
+                        {
+                            Some(action) => format!("/me {}", action),
+                            // a slash would run a command
+                            None if text.starts_with('/') => format!("/{}", text),
+                            None => text.clone(),
+                        };
+                        pipe.write_all(&message(MessageType::Utf8, &[], text.as_bytes()))
+                            .await?;
+                    } else {
+                        let params = format!("{} :Cannot send to channel", CHANNEL);
+                        session.reply("404", &params).await?;
+                    }
+                }
+                (Some(target), Some(_)) => {
+                    let params = format!("{} :Direct messages need a native client", target);
+                    session.reply("401", &params).await?;
+                }
+                _ => session.reply("412", ":No text to send").await?,
+            },
+            "JOIN" => {
+                for channel in params.first().map_or("", String::as_str).split(',') {
+                    if channel != CHANNEL {
+                        let params = format!("{} :No such channel", channel);
+                        session.reply("403", &params).await?;
+                    } else if !session.state.lock().unwrap().joined {
+                        session.join().await?;
+                    }
+                }
+            }
+            "PART" => {
+                let joined = std::mem::take(&mut session.state.lock().unwrap().joined);
+                if joined {
+                    let nick = session.nick();
+                    session
+                        .send(&format!(":{} PART {}", prefix(&nick), CHANNEL))
+                        .await?;
+                    // the channel is the whole chat, so leaving it logs out like `QUIT`
+                    // and the others see the user go
+                    break;
+                } else {
+                    let params = format!("{} :You're not on that channel", CHANNEL);
+                    session.reply("442", &params).await?;
+                }
+            }
+            "NAMES" => session.names().await?,
+            "MODE" | "WHO" | "USERHOST" => {}
+            _ => {
+                let params = format!("{} :Unknown command", command);
+                session.reply("421", &params).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn pipe_to_commands(mut pipe: ReadHalf<DuplexStream>, session: Session) -> io::Result<()> {
+    while let Some((desc, rest)) = read_piped_msg(&mut pipe).await? {
+        let (header, content) = rest.split_at(desc.header_len as usize);
+        let header = serde_json::from_slice::<ServerHeader>(header).unwrap_or_default();
+        let text = String::from_utf8_lossy(content);
+        let (nick, registered, joined) = {
+            let state = session.state.lock().unwrap();
+            (
+                state.nick.clone().unwrap_or_default(),
+                state.registered,
+                state.joined,
+            )
+        };
+        let from = prefix(header.from);
+
+        if !registered {
+            match desc.r#type {
+                MessageType::Login => {
+                    {
+                        let mut state = session.state.lock().unwrap();
+                        state.registered = true;
+                        state.pending = false;
+                    }
+                    let welcome = format!(":Welcome to the chat, {}", nick);
+                    session.reply("001", &welcome).await?;
+                    let host = format!(":Your host is {}", SERVER_NAME);
+                    session.reply("002", &host).await?;
+                    if !session.motd {
+                        session.reply("422", ":MOTD File is missing").await?;
+                    }
+                    session.join().await?;
+                }
+                MessageType::UsernameExists => {
+                    {
+                        let mut state = session.state.lock().unwrap();
+                        state.nick = None;
+                        state.pending = false;
+                    }
+                    let params = format!("{} :Nickname is already in use", nick);
+                    session
+                        .send(&format!(":{} 433 * {}", SERVER_NAME, params))
+                        .await?;
+                }
+                MessageType::BadUsername => {
+                    {
+                        let mut state = session.state.lock().unwrap();
+                        state.nick = None;
+                        state.pending = false;
+                    }
+                    let params = format!("{} :Erroneous nickname", nick);
+                    session
+                        .send(&format!(":{} 432 * {}", SERVER_NAME, params))
+                        .await?;
+                }
+                MessageType::Ban => {
+                    session.send("ERROR :You are banned").await?;
+                    break;
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        let target = if joined { CHANNEL } else { nick.as_str() };
+        match desc.r#type {
+            MessageType::Utf8 if joined && header.from != nick && header.action => {
+                for line in text.lines() {
+                    session
+                        .send(&format!(
+                            ":{} PRIVMSG {} :\x01ACTION {}\x01",
+                            from, CHANNEL, line
+                        ))
+                        .await?;
+                }
+            }
+            MessageType::Utf8 if joined && header.from != nick => {
+                for line in text.lines() {
+                    session
+                        .send(&format!(":{} PRIVMSG {} :{}", from, CHANNEL, line))
+                        .await?;
+                }
+            }
+            MessageType::Image | MessageType::Voice | MessageType::File
+                if joined && header.from != nick =>
+            {
+                let action = match (desc.r#type, header.filename, &header.offer) {
+                    (MessageType::Image, ..) => "sent an image".to_string(),
+                    (MessageType::Voice, ..) => "sent a voice message".to_string(),
+                    (_, Some(name), Some(offer)) => {
+                        format!("offers the file {} ({} bytes)", name, offer.size)
+                    }
+                    _ => "offers a file".to_string(),
+                };
+                session
+                    .send(&format!(
+                        ":{} PRIVMSG {} :\x01ACTION {}\x01",
+                        from, CHANNEL, action
+                    ))
+                    .await?;
+            }
+            MessageType::Nick => {
+                if header.from == nick {
+                    session.state.lock().unwrap().nick = Some(text.to_string());
+                }
+                // users of linked servers are `nick|server` in IRC
+                let new = text.replacen('@', "|", 1);
+                session.send(&format!(":{} NICK :{}", from, new)).await?;
+            }
+            MessageType::Login if joined && header.from != nick => {
+                session.send(&format!(":{} JOIN {}", from, CHANNEL)).await?;
+            }
+            MessageType::Logout if header.from != nick => {
+                session
+                    .send(&format!(":{} QUIT :Left the chat", from))
+                    .await?;
+            }
+            MessageType::ServerNotice => {
+                for line in text.lines() {
+                    session
+                        .send(&format!(":{} NOTICE {} :{}", SERVER_NAME, target, line))
+                        .await?;
+                }
+            }
+            MessageType::Kick | MessageType::Ban => {
+                if let Some(moderation) = header.moderation {
+                    let reason = moderation.reason.unwrap_or_default();
+                    session
+                        .send(&format!(
+                            ":{} KICK {} {} :{}",
+                            from, CHANNEL, moderation.user, reason
+                        ))
+                        .await?;
+                }
+            }
+            MessageType::Mute | MessageType::Unmute | MessageType::Unban => {
+                if let Some(moderation) = header.moderation {
+                    let action = match desc.r#type {
+                        MessageType::Mute => "muted",
+                        MessageType::Unmute => "unmuted",
+                        _ => "unbanned",
+                    };
+                    let line = format!("{} was {} by {}", moderation.user, action, header.from);
+                    session
+                        .send(&format!(":{} NOTICE {} :{}", SERVER_NAME, target, line))
+                        .await?;
+                }
+            }
+            MessageType::Forbidden | MessageType::QuotaExceeded => {
+                let line = if text.is_empty() {
+                    "Forbidden".into()
+                } else {
+                    text
+                };
+                session
+                    .send(&format!(":{} NOTICE {} :{}", SERVER_NAME, nick, line))
+                    .await?;
+            }
+            _ => {}
+        }
+    }
+    let _ = session.send("ERROR :Closing link").await;
+    session.output.lock().await.shutdown().await
+}
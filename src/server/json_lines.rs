@@ -0,0 +1,194 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use serde_json::{json, Map, Value};
+use tokio::{
+    io::{
+        self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream, ReadHalf,
+        WriteHalf,
+    },
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    sync::{mpsc::Sender, Mutex},
+};
+
+use super::{handle_connection, read_piped_msg, Context, InternalMessage, BUF_SIZE};
+use crate::{Descriptor, LoginInfo, MessageType};
+
+type Output = Arc<Mutex<OwnedWriteHalf>>;
+
+/// Longest line a client may send, like the body of a bot post. The connection is dropped
+/// after a longer one, there is no telling where the next message starts.
+const MAX_LINE: u64 = 64 * 1024;
+
+/// Accepts clients speaking one JSON object per line, like `{"type": "utf8", "text": "hi"}`.
+/// Any other fields are the header of the message, messages from the server are written
+/// the same way with the fields of their header.
+pub(super) async fn serve(
+    listener: TcpListener,
+    tx: Sender<InternalMessage>,
+    ctx: Arc<Context>,
+) -> io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let tx = tx.clone();
+        let ctx = Arc::clone(&ctx);
+        tokio::spawn(async move { handle(stream, addr, tx, ctx).await });
+    }
+}
+
+async fn handle(
+    stream: TcpStream,
+    addr: SocketAddr,
+    tx: Sender<InternalMessage>,
+    ctx: Arc<Context>,
+) -> io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let output = Arc::new(Mutex::new(writer));
+    // the connection is handled like a TCP one, on the other end of a pipe
+    let (ours, theirs) = io::duplex(BUF_SIZE);
+    let (our_reader, our_writer) = io::split(ours);
+    tokio::spawn(lines_to_pipe(reader, our_writer, Arc::clone(&output)));
+    tokio::spawn(pipe_to_lines(our_reader, output));
+    let (reader, writer) = io::split(theirs);
+    handle_connection(reader, writer, addr, tx, ctx).await
+}
+
+async fn lines_to_pipe(
+    reader: OwnedReadHalf,
+    mut pipe: WriteHalf<DuplexStream>,
+    output: Output,
+) -> io::Result<()> {
+    let result = read_lines(reader, &mut pipe, output).await;
+    // the connection ends once the server reads to the end of the pipe
+    pipe.shutdown().await?;
+    result
+}
+
+async fn read_lines(
+    reader: OwnedReadHalf,
+    pipe: &mut WriteHalf<DuplexStream>,
+    output: Output,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = (&mut reader)
+            .take(MAX_LINE + 1)
+            .read_line(&mut line)
+            .await?;
+        if read == 0 {
+            break;
+        }
+        if read as u64 > MAX_LINE && !line.ends_with('\n') {
+            write_line(
+                &output,
+                json!({"type": "error", "message": "line too long"}),
+            )
+            .await?;
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        match to_message(&line) {
+            Ok(msg) => pipe.write_all(&msg).await?,
+            Err(message) => {
+                write_line(&output, json!({"type": "error", "message": message})).await?
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn pipe_to_lines(mut pipe: ReadHalf<DuplexStream>, output: Output) -> io::Result<()> {
+    while let Some((desc, rest)) = read_piped_msg(&mut pipe).await? {
+        let (header, content) = rest.split_at(desc.header_len as usize);
+        write_line(&output, to_json(desc.r#type, header, content)).await?;
+    }
+    output.lock().await.shutdown().await
+}
+
+/// Builds a message from a JSON line, only text and admin commands can be sent this way.
+fn to_message(line: &str) -> Result<Vec<u8>, String> {
+    let mut fields: Map<String, Value> = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let t: MessageType = match fields.remove("type") {
+        Some(t) => serde_json::from_value(t).map_err(|e| e.to_string())?,
+        None => return Err("missing type".into()),
+    };
+    let text = match fields.remove("text") {
+        Some(Value::String(text)) => text,
+        Some(_) => return Err("text must be a string".into()),
+        None => String::new(),
+    };
+    let (header, content) = match t {
+        MessageType::Login => match fields.get("username").and_then(Value::as_str) {
+            Some(username) => {
+                // offer no compression, the content of every message is passed on as text
+                let login = LoginInfo {
+                    compression: Vec::new(),
+                    admin_token: fields
+                        .get("admin_token")
+                        .and_then(Value::as_str)
+                        .map(str::to_string),
+                };
+                let content = serde_json::to_vec(&login).unwrap();
+                (username.as_bytes().to_vec(), content)
+            }
+            None => return Err("missing username".into()),
+        },
+        MessageType::Utf8
+        | MessageType::Usage
+        | MessageType::Kick
+        | MessageType::Ban
+        | MessageType::Mute
+        | MessageType::Unban
+        | MessageType::Unmute => {
+            let header = if fields.is_empty() {
+                Vec::new()
+            } else {
+                serde_json::to_vec(&fields).unwrap()
+            };
+            (header, text.into_bytes())
+        }
+        _ => return Err("this type can't be sent as JSON".into()),
+    };
+    let mut msg = Descriptor::from(t)
+        .with_header_len(header.len() as u16)
+        .with_content_len(content.len() as u64)
+        .as_bytes()
+        .to_vec();
+    msg.extend_from_slice(&header);
+    msg.extend_from_slice(&content);
+    Ok(msg)
+}
+
+/// Flattens a message into its type, the fields of its header and its content as `text`.
+/// Binary contents are only described by their length.
+pub(super) fn to_json(t: MessageType, header: &[u8], content: &[u8]) -> Value {
+    let mut fields = Map::new();
+    match serde_json::from_slice(header) {
+        Ok(Value::Object(header)) => fields.extend(header),
+        _ if header.is_empty() => {}
+        _ => {
+            let header = String::from_utf8_lossy(header).into_owned();
+            fields.insert("header".into(), Value::String(header));
+        }
+    }
+    if !content.is_empty() {
+        match std::str::from_utf8(content) {
+            Ok(text) => fields.insert("text".into(), text.into()),
+            Err(_) => fields.insert("content_len".into(), content.len().into()),
+        };
+    }
+    fields.insert("type".into(), serde_json::to_value(t).unwrap());
+    Value::Object(fields)
+}
+
+async fn write_line(output: &Output, value: Value) -> io::Result<()> {
+    let mut line = serde_json::to_vec(&value).unwrap();
+    line.push(b'\n');
+    output.lock().await.write_all(&line).await
+}
@@ -0,0 +1,390 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        oneshot,
+    },
+};
+use tracing::{debug, info, warn};
+
+use super::{read_content, send_msg, skip, Content, Context, InternalMessage, BUF_SIZE};
+use crate::{compression::Compression, Descriptor, LinkInfo, MessageType, ServerHeader};
+
+/// How long to wait before connecting to a linked server again.
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// How many relayed messages are remembered to drop those arriving again.
+const SEEN_CAPACITY: usize = 1024;
+
+/// Messages relayed from linked servers recently. When links form a cycle the same message
+/// arrives over more than one of them, it is only shown the first time.
+#[derive(Debug, Default)]
+pub(super) struct Seen {
+    order: VecDeque<(String, DateTime<Utc>)>,
+    keys: HashSet<(String, DateTime<Utc>)>,
+}
+
+impl Seen {
+    /// Whether a message with this header arrives for the first time, remembering it if so.
+    pub(super) fn first_time(&mut self, header: &[u8]) -> bool {
+        let key = match serde_json::from_slice::<ServerHeader>(header) {
+            Ok(header) => (header.from.to_string(), header.timestamp),
+            Err(_) => return true,
+        };
+        if !self.keys.insert(key.clone()) {
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() > SEEN_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Accepts links from other servers.
+pub(super) async fn serve(
+    listener: TcpListener,
+    tx: Sender<InternalMessage>,
+    ctx: Arc<Context>,
+) -> io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let tx = tx.clone();
+        let ctx = Arc::clone(&ctx);
+        tokio::spawn(async move {
+            if let Err(e) = accept(stream, addr, tx, ctx).await {
+                warn!(%addr, error = %e, "link failed");
+            }
+        });
+    }
+}
+
+/// Keeps a link to the server at `addr`, connecting again whenever it is lost.
+pub(super) async fn connect(addr: String, tx: Sender<InternalMessage>, ctx: Arc<Context>) {
+    loop {
+        match open(&addr, &tx, &ctx).await {
+            Ok(()) => info!(%addr, "link closed"),
+            Err(e) => warn!(%addr, error = %e, "link failed"),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn accept(
+    stream: TcpStream,
+    addr: SocketAddr,
+    tx: Sender<InternalMessage>,
+    ctx: Arc<Context>,
+) -> io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let desc = Descriptor::read(Pin::new(&mut reader)).await?;
+    if desc.r#type != MessageType::Link {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    let info = read_info(&mut reader, desc).await?;
+
+    let (server_name, secret) = {
+        let config = ctx.config();
+        (config.server_name.clone(), config.link_secret.clone())
+    };
+    // the secret may have been removed by a reload
+    if secret.is_none() || info.secret != secret {
+        warn!(%addr, server = %info.server, "refused a link with a wrong secret");
+        return send_msg(
+            &mut writer,
+            Descriptor::from(MessageType::Forbidden),
+            None,
+            None,
+        )
+        .await;
+    }
+    let server = Arc::new(info.server);
+    let rx = match register(&server, &tx).await {
+        Some(rx) => rx,
+        None => {
+            warn!(%addr, %server, "refused a link from a server with a taken name");
+            let desc = Descriptor::from(MessageType::Forbidden);
+            return send_msg(&mut writer, desc, None, None).await;
+        }
+    };
+    let header = serde_json::to_vec(&LinkInfo {
+        server: server_name,
+        secret: None,
+    })
+    .unwrap();
+    let desc = Descriptor::from(MessageType::Link).with_header_len(header.len() as u16);
+    send_msg(&mut writer, desc, Some(Arc::new(header)), None).await?;
+    run(reader, writer, server, rx, tx, ctx).await
+}
+
+async fn open(addr: &str, tx: &Sender<InternalMessage>, ctx: &Arc<Context>) -> io::Result<()> {
+    let info = {
+        let config = ctx.config();
+        LinkInfo {
+            server: config.server_name.clone(),
+            secret: config.link_secret.clone(),
+        }
+    };
+    if info.secret.is_none() {
+        let e = "no link secret to present";
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, e));
+    }
+    let stream = TcpStream::connect(addr).await?;
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let header = serde_json::to_vec(&info).unwrap();
+    let desc = Descriptor::from(MessageType::Link).with_header_len(header.len() as u16);
+    send_msg(&mut writer, desc, Some(Arc::new(header)), None).await?;
+
+    let desc = Descriptor::read(Pin::new(&mut reader)).await?;
+    if desc.r#type != MessageType::Link {
+        let e = format!("refused with {:?}", desc.r#type);
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, e));
+    }
+    let server = Arc::new(read_info(&mut reader, desc).await?.server);
+    let rx = match register(&server, tx).await {
+        Some(rx) => rx,
+        None => {
+            let e = format!(
+                "{} is already linked or has the name of this server",
+                server
+            );
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, e));
+        }
+    };
+    run(reader, writer, server, rx, tx.clone(), Arc::clone(ctx)).await
+}
+
+async fn read_info(
+    reader: &mut BufReader<OwnedReadHalf>,
+    desc: Descriptor,
+) -> io::Result<LinkInfo> {
+    let mut header = vec![0; desc.header_len as usize];
+    reader.read_exact(&mut header).await?;
+    skip(reader, desc.content_len).await?;
+    match serde_json::from_slice::<LinkInfo>(&header) {
+        Ok(info) if is_server_name(&info.server) => Ok(info),
+        _ => Err(io::ErrorKind::InvalidData.into()),
+    }
+}
+
+fn is_server_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(|c: char| c == '@' || c.is_whitespace())
+}
+
+/// Adds the link to the server task, `None` if a server with the same name is linked already.
+async fn register(
+    server: &Arc<String>,
+    tx: &Sender<InternalMessage>,
+) -> Option<Receiver<InternalMessage>> {
+    let (sender, rx) = channel(128);
+    let (resp, recv) = oneshot::channel();
+    tx.send(InternalMessage::LinkUp {
+        server: Arc::clone(server),
+        sender,
+        resp,
+    })
+    .await
+    .unwrap();
+    recv.await.ok().filter(|&up| up).map(|_| rx)
+}
+
+/// Relays messages both ways until either side closes the link.
+async fn run(
+    mut reader: BufReader<OwnedReadHalf>,
+    writer: BufWriter<OwnedWriteHalf>,
+    server: Arc<String>,
+    rx: Receiver<InternalMessage>,
+    tx: Sender<InternalMessage>,
+    ctx: Arc<Context>,
+) -> io::Result<()> {
+    let server_name = ctx.config().server_name.clone();
+    let result = tokio::select! {
+        read = read_messages(&mut reader, &server, &tx, &ctx, &server_name) => read,
+        written = write_messages(writer, rx, &server_name) => written,
+    };
+    tx.send(InternalMessage::LinkDown { server }).await.unwrap();
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(()),
+        result => result,
+    }
+}
+
+async fn read_messages(
+    reader: &mut BufReader<OwnedReadHalf>,
+    server: &Arc<String>,
+    tx: &Sender<InternalMessage>,
+    ctx: &Context,
+    server_name: &str,
+) -> io::Result<()> {
+    loop {
+        let desc = Descriptor::read(Pin::new(&mut *reader)).await?;
+        if !is_relayed(desc.r#type) || desc.compression != Compression::None {
+            warn!(%server, msg_type = ?desc.r#type, "unexpected message on a link");
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let mut raw_header = vec![0; desc.header_len as usize];
+        reader.read_exact(&mut raw_header).await?;
+        // remote users are always qualified with the name of their server
+        let (from, looped) = match serde_json::from_slice::<ServerHeader>(&raw_header) {
+            Ok(header) if is_from_peer(&header, server) => (
+                header.from.to_string(),
+                header.via.iter().any(|s| s == server_name),
+            ),
+            _ => {
+                warn!(%server, "message with a sender the link can't vouch for");
+                return Err(io::ErrorKind::InvalidData.into());
+            }
+        };
+        if looped {
+            debug!(%server, "dropped a message that came around");
+            skip(reader, desc.content_len).await?;
+            continue;
+        }
+
+        let upload = matches!(desc.r#type, MessageType::Image | MessageType::Voice);
+        let spool = desc.content_len > BUF_SIZE as u64;
+        let fits = if upload {
+            ctx.check_upload(desc.content_len)
+        } else {
+            Ok(())
+        }
+        .and_then(|_| {
+            if spool {
+                ctx.storage.reserve(&from, desc.content_len)
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = fits {
+            debug!(%server, user = %from, error = ?e, "dropped a relayed message");
+            skip(reader, desc.content_len).await?;
+            continue;
+        }
+        let owner = spool.then_some((from.as_str(), &ctx.storage));
+        let (content, _) = read_content(reader, desc.content_len, owner).await?;
+        if desc.r#type == MessageType::Nick && !is_rename_on_same_server(&from, &content) {
+            warn!(%server, user = %from, "rename to a user of another server");
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let message = InternalMessage::Message {
+            desc,
+            header: Arc::new(raw_header),
+            content,
+        };
+        tx.send(InternalMessage::Linked {
+            server: Arc::clone(server),
+            message: Box::new(message),
+        })
+        .await
+        .unwrap();
+    }
+}
+
+async fn write_messages(
+    mut writer: BufWriter<OwnedWriteHalf>,
+    mut rx: Receiver<InternalMessage>,
+    server_name: &str,
+) -> io::Result<()> {
+    while let Some(msg) = rx.recv().await {
+        if let InternalMessage::Message {
+            desc,
+            header,
+            content,
+        } = msg
+        {
+            if !is_relayed(desc.r#type) {
+                continue;
+            }
+            let header = match tag(&header, server_name) {
+                Some(header) => header,
+                None => continue,
+            };
+            let desc = desc.with_header_len(header.len() as u16);
+            // the new name of a user of this server is qualified like the old one
+            let (desc, content) = match (desc.r#type, &content) {
+                (MessageType::Nick, Content::Vec(new, _)) if !new.contains(&b'@') => {
+                    let new = [new.as_slice(), b"@", server_name.as_bytes()].concat();
+                    (desc.with_content_len(new.len() as u64), Content::vec(new))
+                }
+                _ => (desc, content),
+            };
+            content
+                .write(Pin::new(&mut writer), desc, &header, Compression::None)
+                .await?;
+            writer.flush().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether messages of type `t` go over links, others only make sense on their own server.
+fn is_relayed(t: MessageType) -> bool {
+    matches!(
+        t,
+        MessageType::Utf8
+            | MessageType::Image
+            | MessageType::Voice
+            | MessageType::Login
+            | MessageType::Logout
+            | MessageType::Nick
+    )
+}
+
+/// Whether the sender of a message arriving over the link to `peer` is a user of the server
+/// the message started at, and `peer` is the last server it went through. Servers further
+/// away are trusted to have checked the same on their links.
+fn is_from_peer(header: &ServerHeader, peer: &str) -> bool {
+    let origin = match header.from.split_once('@') {
+        Some((name, origin)) if !name.is_empty() => origin,
+        _ => return false,
+    };
+    header.via.first().is_some_and(|s| s == origin) && header.via.last().is_some_and(|s| s == peer)
+}
+
+/// Whether the content of a `Nick` from `from` is a new name on the same server.
+fn is_rename_on_same_server(from: &str, content: &Content) -> bool {
+    let new = match content {
+        Content::Vec(new, _) => String::from_utf8_lossy(new),
+        _ => return false,
+    };
+    match (from.split_once('@'), new.split_once('@')) {
+        (Some((_, origin)), Some((name, server))) => {
+            !name.is_empty() && server == origin && !name.contains(char::is_whitespace)
+        }
+        _ => false,
+    }
+}
+
+/// Qualifies the sender of a message sent on this server with its name, and adds the name to
+/// the servers the message went through.
+fn tag(header: &[u8], server_name: &str) -> Option<Vec<u8>> {
+    let qualified;
+    let mut header = serde_json::from_slice::<ServerHeader>(header).ok()?;
+    if header.via.is_empty() {
+        qualified = format!("{}@{}", header.from, server_name);
+        header.from = &qualified;
+    }
+    header.via.push(server_name.to_string());
+    Some(serde_json::to_vec(&header).unwrap())
+}
@@ -0,0 +1,240 @@
+use std::{collections::HashMap, fmt::Write, sync::Mutex, time::Duration};
+
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc::Sender, oneshot},
+    time::timeout,
+};
+use tracing::debug;
+
+use super::InternalMessage;
+use crate::{MessageType, UsageReport};
+
+/// Longest request line and headers of a scrape.
+const MAX_HEAD: u64 = 8 * 1024;
+
+/// How long a scraper may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Counters kept since the start of the server.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Messages and content bytes relayed, by type.
+    relayed: Mutex<HashMap<MessageType, (u64, u64)>>,
+    login_failures: Mutex<HashMap<&'static str, u64>>,
+}
+
+/// What is only known to the server task, collected when metrics are scraped.
+#[derive(Debug)]
+pub struct Snapshot {
+    pub offers: usize,
+    /// Messages waiting to be written, by user.
+    pub queues: Vec<(String, usize)>,
+    pub storage: UsageReport,
+}
+
+impl Metrics {
+    pub fn relayed(&self, t: MessageType, bytes: u64) {
+        let mut relayed = self.relayed.lock().unwrap();
+        let (messages, total) = relayed.entry(t).or_default();
+        *messages += 1;
+        *total += bytes;
+    }
+
+    pub fn login_failed(&self, reason: &'static str) {
+        *self
+            .login_failures
+            .lock()
+            .unwrap()
+            .entry(reason)
+            .or_default() += 1;
+    }
+
+    /// Renders everything in the Prometheus text format.
+    pub fn render(&self, snapshot: &Snapshot) -> String {
+        let mut out = String::new();
+        gauge(&mut out, "chat_connected_users", "Users logged in.");
+        sample(
+            &mut out,
+            "chat_connected_users",
+            &[],
+            snapshot.queues.len() as u64,
+        );
+
+        let mut relayed: Vec<_> = self
+            .relayed
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(t, counts)| (format!("{:?}", t), *counts))
+            .collect();
+        relayed.sort();
+        counter(
+            &mut out,
+            "chat_messages_relayed_total",
+            "Messages relayed, by type.",
+        );
+        for (t, (messages, _)) in &relayed {
+            sample(
+                &mut out,
+                "chat_messages_relayed_total",
+                &[("type", t)],
+                *messages,
+            );
+        }
+        counter(
+            &mut out,
+            "chat_bytes_relayed_total",
+            "Content bytes relayed, by message type.",
+        );
+        for (t, (_, bytes)) in &relayed {
+            sample(&mut out, "chat_bytes_relayed_total", &[("type", t)], *bytes);
+        }
+
+        gauge(
+            &mut out,
+            "chat_spool_bytes",
+            "Bytes of uploads kept in the spool.",
+        );
+        sample(&mut out, "chat_spool_bytes", &[], snapshot.storage.total);
+        gauge(
+            &mut out,
+            "chat_spool_available_bytes",
+            "Free space on the spool disk.",
+        );
+        sample(
+            &mut out,
+            "chat_spool_available_bytes",
+            &[],
+            snapshot.storage.available,
+        );
+        gauge(&mut out, "chat_offers", "Files offered for download.");
+        sample(&mut out, "chat_offers", &[], snapshot.offers as u64);
+
+        gauge(
+            &mut out,
+            "chat_queue_depth",
+            "Messages waiting to be written, by user.",
+        );
+        for (user, depth) in &snapshot.queues {
+            sample(
+                &mut out,
+                "chat_queue_depth",
+                &[("user", user)],
+                *depth as u64,
+            );
+        }
+
+        let mut failures: Vec<_> = self
+            .login_failures
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(reason, count)| (*reason, *count))
+            .collect();
+        failures.sort();
+        counter(
+            &mut out,
+            "chat_login_failures_total",
+            "Refused logins, by reason.",
+        );
+        for (reason, count) in failures {
+            sample(
+                &mut out,
+                "chat_login_failures_total",
+                &[("reason", reason)],
+                count,
+            );
+        }
+        out
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge", name, help, name);
+}
+
+fn counter(out: &mut String, name: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: u64) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        let labels: Vec<_> = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+            .collect();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
+
+/// Answers `GET /metrics` over plain HTTP, one request per connection.
+pub(super) async fn serve(listener: TcpListener, tx: Sender<InternalMessage>) -> io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            match timeout(REQUEST_TIMEOUT, handle(stream, tx)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!(%addr, error = %e, "metrics request failed"),
+                Err(_) => debug!(%addr, "metrics request timed out"),
+            }
+        });
+    }
+}
+
+async fn handle(stream: TcpStream, tx: Sender<InternalMessage>) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut head = BufReader::new(reader).take(MAX_HEAD);
+    let mut request = String::new();
+    head.read_line(&mut request).await?;
+    // the headers don't matter, but must end within the limit
+    let mut complete = false;
+    loop {
+        let mut line = String::new();
+        if head.read_line(&mut line).await? == 0 {
+            break;
+        }
+        if line.trim_end().is_empty() {
+            complete = true;
+            break;
+        }
+    }
+
+    let mut parts = request.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        _ if !complete => response("431 Request Header Fields Too Large", "text/plain", ""),
+        (Some("GET"), Some("/metrics")) => {
+            let (resp, recv) = oneshot::channel();
+            let _ = tx.send(InternalMessage::Metrics { resp }).await;
+            match recv.await {
+                Ok(body) => response("200 OK", "text/plain; version=0.0.4", &body),
+                Err(_) => response("503 Service Unavailable", "text/plain", ""),
+            }
+        }
+        (Some("GET"), _) => response("404 Not Found", "text/plain", ""),
+        _ => response("405 Method Not Allowed", "text/plain", ""),
+    };
+    writer.write_all(response.as_bytes()).await?;
+    writer.shutdown().await
+}
+
+pub(super) fn response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
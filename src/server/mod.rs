@@ -0,0 +1,2200 @@
+mod bots;
+mod commands;
+mod config;
+mod control;
+mod irc;
+mod json_lines;
+mod link;
+mod metrics;
+mod moderation;
+mod storage;
+mod websocket;
+
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    io::{Cursor, SeekFrom},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex, RwLock, RwLockReadGuard},
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{
+        self, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader,
+        BufWriter,
+    },
+    net::{TcpListener, ToSocketAddrs},
+    signal::unix::{signal, SignalKind},
+    sync::{
+        mpsc::{channel, error::TryRecvError, Receiver, Sender},
+        oneshot, watch, OnceCell,
+    },
+    task::JoinSet,
+};
+use tracing::{debug, error, info, trace, warn, Instrument, Span};
+
+use metrics::{Metrics, Snapshot};
+
+const BUF_SIZE: usize = 16 * 1024;
+
+/// Messages waiting to be written to a connection.
+const CONN_QUEUE: usize = 128;
+
+/// Most messages kept in the history, so that they and the message of the day fit in the
+/// queue of a new connection before it is written to.
+const MAX_HISTORY: usize = CONN_QUEUE - 1;
+
+/// How often partial uploads are checked for expiry.
+const PARTIAL_SWEEP: Duration = Duration::from_secs(10 * 60);
+
+/// Login content only lists compression algorithms and the admin token, anything longer is ignored.
+const MAX_LOGIN_CONTENT: u64 = 1024;
+
+/// Largest message a gateway translates at once. Bigger contents are always streamed
+/// in chunks, so only a broken connection could produce one.
+const MAX_PIPED_MSG: u64 = 2 * CHUNK_SIZE as u64;
+
+use crate::{
+    compression::{self, Compression},
+    crypto,
+    media::{ImageInfo, VoiceInfo},
+    sha256_file, Descriptor, Direct, FileOffer, LoginInfo, MessageType, Moderation, PublicKey,
+    ServerHeader, Transfer, CHUNK_SIZE, SYSTEM_USER,
+};
+pub use commands::{Command, CommandContext, CommandFuture, Commands};
+pub use config::{Bot, ConfigError, ConfigFile, Reload, ServerConfig, Webhook};
+pub use control::{ControlRequest, ControlResponse, Stats, UserInfo};
+pub use moderation::{Ban, BanFile, BanList, Mutes};
+pub use storage::{QuotaError, SpoolFile, Storage};
+
+/// State shared by all connections.
+struct Context {
+    config: RwLock<ServerConfig>,
+    reload: Option<Reload>,
+    storage: Arc<Storage>,
+    bans: Mutex<BanList>,
+    mutes: Mutex<Mutes>,
+    metrics: Metrics,
+    commands: Commands,
+}
+
+impl Context {
+    fn config(&self) -> RwLockReadGuard<'_, ServerConfig> {
+        self.config.read().unwrap()
+    }
+
+    /// Whether `uname` is an admin and logged in with the admin token.
+    fn is_admin(&self, uname: &str, token: Option<&str>) -> bool {
+        let config = self.config();
+        let admin = config.admins.iter().any(|a| a == uname);
+        admin && config.admin_token.is_some() && config.admin_token.as_deref() == token
+    }
+
+    /// The name of the bot with this token.
+    fn bot(&self, token: &str) -> Option<String> {
+        let config = self.config();
+        let bot = config.bots.iter().find(|b| b.token == token)?;
+        Some(bot.name.clone())
+    }
+
+    fn is_bot(&self, uname: &str) -> bool {
+        self.config().bots.iter().any(|b| b.name == uname)
+    }
+
+    /// Refuses uploads larger than the configured maximum.
+    fn check_upload(&self, size: u64) -> Result<(), QuotaError> {
+        match self.config().max_upload_size {
+            Some(max) if size > max => Err(QuotaError::TooLarge),
+            _ => Ok(()),
+        }
+    }
+
+    /// Builds the configuration again and reads the ban list from disk. Connections are kept,
+    /// the spool directory, the ban file and the listening sockets only change on restart.
+    fn reload(&self) -> Result<(), ConfigError> {
+        if let Some(reload) = &self.reload {
+            let mut config = reload()?;
+            let mut current = self.config.write().unwrap();
+            config.spool_dir = current.spool_dir.clone();
+            config.ban_file = current.ban_file.clone();
+            config.control_socket = current.control_socket.clone();
+            config.metrics_address = current.metrics_address.clone();
+            config.websocket_address = current.websocket_address.clone();
+            config.json_address = current.json_address.clone();
+            config.irc_address = current.irc_address.clone();
+            config.server_name = current.server_name.clone();
+            config.link_address = current.link_address.clone();
+            config.links = current.links.clone();
+            config.bot_address = current.bot_address.clone();
+            self.storage.set_limits(
+                config.user_quota,
+                config.global_quota,
+                config.min_free_space,
+            );
+            *current = config;
+        }
+        self.bans.lock().unwrap().reload()?;
+        Ok(())
+    }
+
+    /// [`Context::reload`] on a blocking thread, as it reads files.
+    async fn reload_blocking(self: Arc<Self>) -> Result<(), ConfigError> {
+        tokio::task::spawn_blocking(move || self.reload())
+            .await
+            .map_err(io::Error::from)?
+    }
+}
+
+/// The content of a message, shared by every recipient along with its compressed forms.
+#[derive(Debug, Clone)]
+enum Content {
+    Vec(Arc<Vec<u8>>, Arc<Compressed>),
+    File(Arc<SpoolFile>, Arc<Compressed>),
+    None,
+}
+
+/// A content compressed with each algorithm, made by the first recipient that needs it.
+/// `None` inside means compressing doesn't pay off.
+#[derive(Debug, Default)]
+struct Compressed {
+    zstd: OnceCell<Option<Arc<Vec<u8>>>>,
+    deflate: OnceCell<Option<Arc<Vec<u8>>>>,
+}
+
+impl Compressed {
+    fn get(&self, compression: Compression) -> Option<&OnceCell<Option<Arc<Vec<u8>>>>> {
+        match compression {
+            Compression::Zstd => Some(&self.zstd),
+            Compression::Deflate => Some(&self.deflate),
+            Compression::None | Compression::Unknown => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct StoredOffer {
+    desc: Descriptor,
+    header: Arc<Vec<u8>>,
+    content: Content,
+    expires: Instant,
+}
+
+#[derive(Debug)]
+enum InternalMessage {
+    Message {
+        desc: Descriptor,
+        header: Arc<Vec<u8>>,
+        content: Content,
+    },
+    Join {
+        username: Arc<String>,
+        addr: SocketAddr,
+        admin: bool,
+        resp: oneshot::Sender<MessageType>,
+        sender: Sender<InternalMessage>,
+    },
+    Logout {
+        username: Arc<String>,
+    },
+    /// A file to keep until someone accepts it; `desc` and `header` describe the `File` message.
+    Offer {
+        id: String,
+        desc: Descriptor,
+        header: Arc<Vec<u8>>,
+        content: Content,
+    },
+    Accept {
+        id: String,
+        sender: Sender<InternalMessage>,
+    },
+    Download {
+        transfer: Transfer,
+        sender: Sender<InternalMessage>,
+    },
+    PublishKey {
+        username: Arc<String>,
+        key: String,
+    },
+    KeyRequest {
+        user: String,
+        sender: Sender<InternalMessage>,
+    },
+    /// `Kick`, `Ban`, `Mute`, `Unban` or `Unmute` from an admin.
+    Moderate {
+        r#type: MessageType,
+        admin: Arc<String>,
+        moderation: Moderation,
+        sender: Sender<InternalMessage>,
+    },
+    /// A command from the control socket.
+    Control {
+        request: ControlRequest,
+        resp: oneshot::Sender<ControlResponse>,
+    },
+    /// A scrape of the metrics endpoint.
+    Metrics {
+        resp: oneshot::Sender<String>,
+    },
+    /// An encrypted `message` for a single user, relayed as is.
+    Direct {
+        to: String,
+        message: Box<InternalMessage>,
+        sender: Sender<InternalMessage>,
+    },
+    /// A link to another server is up, `resp` is `false` if one with the same name already is.
+    LinkUp {
+        server: Arc<String>,
+        sender: Sender<InternalMessage>,
+        resp: oneshot::Sender<bool>,
+    },
+    LinkDown {
+        server: Arc<String>,
+    },
+    /// `/nick`, `resp` is `Nick` once the user is renamed, `Forbidden` while the user is
+    /// muted or keeps files in the spool.
+    Rename {
+        old: Arc<String>,
+        new: Arc<String>,
+        resp: oneshot::Sender<MessageType>,
+    },
+    /// A `message` relayed from a linked server.
+    Linked {
+        server: Arc<String>,
+        message: Box<InternalMessage>,
+    },
+}
+
+impl InternalMessage {
+    fn try_clone(&self) -> Option<Self> {
+        match &self {
+            Self::Message {
+                desc,
+                header,
+                content,
+            } => Some(Self::Message {
+                desc: *desc,
+                header: Arc::clone(header),
+                content: content.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub async fn run_server(addrs: impl ToSocketAddrs, config: ServerConfig) -> io::Result<()> {
+    let listener = TcpListener::bind(addrs).await?;
+    run_server_on(vec![listener], config, None, Commands::default()).await
+}
+
+/// Serves the chat on all `listeners`. With `reload` the configuration is built again on
+/// `SIGHUP` and by the control socket's `reload` command, otherwise only the ban list is reread.
+/// Users can run `commands` by sending text starting with a slash.
+pub async fn run_server_on(
+    listeners: Vec<TcpListener>,
+    config: ServerConfig,
+    reload: Option<Reload>,
+    commands: Commands,
+) -> io::Result<()> {
+    let (tx, rx) = channel(128);
+
+    tokio::fs::create_dir_all(&config.spool_dir).await?;
+    let storage = Arc::new(Storage::new(
+        config.spool_dir.clone(),
+        config.user_quota,
+        config.global_quota,
+        config.min_free_space,
+    ));
+    storage.rebuild()?;
+    let bans = Mutex::new(BanList::load(config.ban_file.clone())?);
+    let control_socket = config.control_socket.clone();
+    let metrics_address = config.metrics_address.clone();
+    let websocket_address = config.websocket_address.clone();
+    let json_address = config.json_address.clone();
+    let irc_address = config.irc_address.clone();
+    let link_address = config.link_address.clone();
+    let links = config.links.clone();
+    // anyone could link otherwise, and speak for the users of a made-up server
+    if (link_address.is_some() || !links.is_empty()) && config.link_secret.is_none() {
+        let e = "links need a link secret";
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+    }
+    let bot_address = config.bot_address.clone();
+    let ctx = Arc::new(Context {
+        config: RwLock::new(config),
+        reload,
+        storage,
+        bans,
+        mutes: Mutex::default(),
+        metrics: Metrics::default(),
+        commands,
+    });
+
+    let tx_c = tx.clone();
+    let ctx_c = Arc::clone(&ctx);
+    tokio::spawn(async move {
+        if let Err(e) = server_task(rx, tx_c, ctx_c).await {
+            error!(error = %e, "server task failed");
+        }
+    });
+
+    if let Some(path) = control_socket {
+        let tx = tx.clone();
+        info!(path = %path.display(), "control socket");
+        tokio::spawn(async move {
+            if let Err(e) = control::serve(path, tx).await {
+                error!(error = %e, "control socket failed");
+            }
+        });
+    }
+
+    if let Some(addr) = metrics_address {
+        let listener = TcpListener::bind(&addr).await?;
+        info!(%addr, "metrics endpoint");
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(listener, tx).await {
+                error!(error = %e, "metrics endpoint failed");
+            }
+        });
+    }
+
+    if let Some(addr) = websocket_address {
+        let listener = TcpListener::bind(&addr).await?;
+        info!(%addr, "listening for websockets");
+        let tx = tx.clone();
+        let ctx = Arc::clone(&ctx);
+        tokio::spawn(async move {
+            if let Err(e) = websocket::serve(listener, tx, ctx).await {
+                error!(error = %e, "websocket listener failed");
+            }
+        });
+    }
+
+    if let Some(addr) = json_address {
+        let listener = TcpListener::bind(&addr).await?;
+        info!(%addr, "listening for JSON lines");
+        let tx = tx.clone();
+        let ctx = Arc::clone(&ctx);
+        tokio::spawn(async move {
+            if let Err(e) = json_lines::serve(listener, tx, ctx).await {
+                error!(error = %e, "JSON lines listener failed");
+            }
+        });
+    }
+
+    if let Some(addr) = irc_address {
+        let listener = TcpListener::bind(&addr).await?;
+        info!(%addr, "listening for IRC clients");
+        let tx = tx.clone();
+        let ctx = Arc::clone(&ctx);
+        tokio::spawn(async move {
+            if let Err(e) = irc::serve(listener, tx, ctx).await {
+                error!(error = %e, "IRC listener failed");
+            }
+        });
+    }
+
+    if let Some(addr) = link_address {
+        let listener = TcpListener::bind(&addr).await?;
+        info!(%addr, "listening for links");
+        let tx = tx.clone();
+        let ctx = Arc::clone(&ctx);
+        tokio::spawn(async move {
+            if let Err(e) = link::serve(listener, tx, ctx).await {
+                error!(error = %e, "link listener failed");
+            }
+        });
+    }
+
+    if let Some(addr) = bot_address {
+        let listener = TcpListener::bind(&addr).await?;
+        info!(%addr, "listening for bots");
+        let tx = tx.clone();
+        let ctx = Arc::clone(&ctx);
+        tokio::spawn(async move {
+            if let Err(e) = bots::serve(listener, tx, ctx).await {
+                error!(error = %e, "bot endpoint failed");
+            }
+        });
+    }
+
+    for addr in links {
+        tokio::spawn(link::connect(addr, tx.clone(), Arc::clone(&ctx)));
+    }
+
+    let ctx_c = Arc::clone(&ctx);
+    tokio::spawn(async move {
+        let mut sweep = tokio::time::interval(PARTIAL_SWEEP);
+        loop {
+            sweep.tick().await;
+            let ttl = ctx_c.config().offer_ttl;
+            let storage = Arc::clone(&ctx_c.storage);
+            let expired = tokio::task::spawn_blocking(move || storage.expire_partials(ttl)).await;
+            if let Ok(Err(e)) = expired {
+                warn!(error = %e, "failed to expire partial uploads");
+            }
+        }
+    });
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    let ctx_c = Arc::clone(&ctx);
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match Arc::clone(&ctx_c).reload_blocking().await {
+                Ok(()) => info!("configuration reloaded"),
+                Err(e) => error!(error = %e, "failed to reload the configuration"),
+            }
+        }
+    });
+
+    let mut accepts = JoinSet::new();
+    for listener in listeners {
+        if let Ok(addr) = listener.local_addr() {
+            info!(%addr, "listening");
+        }
+        let tx = tx.clone();
+        let ctx = Arc::clone(&ctx);
+        accepts.spawn(accept(listener, tx, ctx));
+    }
+    while let Some(accept) = accepts.join_next().await {
+        accept.unwrap()?;
+    }
+    Ok(())
+}
+
+async fn accept(
+    listener: TcpListener,
+    tx: Sender<InternalMessage>,
+    ctx: Arc<Context>,
+) -> io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let tx = tx.clone();
+        let ctx = Arc::clone(&ctx);
+        let (reader, writer) = stream.into_split();
+        tokio::spawn(async move { handle_connection(reader, writer, addr, tx, ctx).await });
+    }
+}
+
+#[tracing::instrument(name = "server", skip_all)]
+async fn server_task(
+    mut rx: Receiver<InternalMessage>,
+    tx: Sender<InternalMessage>,
+    ctx: Arc<Context>,
+) -> io::Result<()> {
+    let mut map = HashMap::<Arc<String>, Peer>::new();
+    let mut offers = HashMap::<String, StoredOffer>::new();
+    // public keys of online users
+    let mut keys = HashMap::<Arc<String>, String>::new();
+    // recent text messages, replayed to users as they join
+    let mut history = VecDeque::<InternalMessage>::new();
+    // linked servers by name
+    let mut links = HashMap::<Arc<String>, Sender<InternalMessage>>::new();
+    // users of linked servers that logged in, by the link they came over
+    let mut remote_users = HashMap::<Arc<String>, HashSet<String>>::new();
+    let mut seen = link::Seen::default();
+    let webhooks = bots::Webhooks::spawn();
+    let started = Instant::now();
+    let mut relayed = 0u64;
+    while let Some(msg) = rx.recv().await {
+        // dropping an expired offer removes its spool file
+        let now = Instant::now();
+        offers.retain(|_, offer| offer.expires > now);
+        match msg {
+            msg @ InternalMessage::Message { .. } => {
+                relayed += 1;
+                remember(&ctx, &mut history, &msg);
+                webhooks.post(&ctx, &msg);
+                relay(&links, &msg, None).await;
+                broadcast(&map, msg).await;
+            }
+            InternalMessage::Rename { old, new, resp } => {
+                if map.contains_key(&new) {
+                    let _ = resp.send(MessageType::UsernameExists);
+                    continue;
+                }
+                // quotas, partial uploads and offers are kept by name, and mutes stay
+                // with the old one
+                let muted = ctx.mutes.lock().unwrap().is_muted(&old);
+                if muted || ctx.storage.used_by(&old) > 0 {
+                    let _ = resp.send(MessageType::Forbidden);
+                    continue;
+                }
+                if let Some(peer) = map.remove(&old) {
+                    map.insert(Arc::clone(&new), peer);
+                    if let Some(key) = keys.remove(&old) {
+                        keys.insert(Arc::clone(&new), key);
+                    }
+                    let header = ServerHeader::default()
+                        .with_username(old.as_str())
+                        .to_json();
+                    tx.send(InternalMessage::Message {
+                        desc: Descriptor::from(MessageType::Nick)
+                            .with_header_len(header.len() as u16)
+                            .with_content_len(new.len() as u64),
+                        header: Arc::new(header),
+                        content: Content::vec(new.as_bytes().to_vec()),
+                    })
+                    .await
+                    .unwrap();
+                }
+                let _ = resp.send(MessageType::Nick);
+            }
+            InternalMessage::Linked { server, message } => {
+                if let InternalMessage::Message {
+                    desc,
+                    header,
+                    content,
+                } = &*message
+                {
+                    if !seen.first_time(header) {
+                        debug!(%server, "dropped a message that arrived over another link");
+                        continue;
+                    }
+                    let from = serde_json::from_slice::<ServerHeader>(header)
+                        .map(|h| h.from.to_string())
+                        .unwrap_or_default();
+                    let users = remote_users.entry(Arc::clone(&server)).or_default();
+                    match desc.r#type {
+                        MessageType::Login => {
+                            users.insert(from);
+                        }
+                        MessageType::Logout => {
+                            users.remove(&from);
+                        }
+                        // so that the user is logged out under the new name if the link goes down
+                        MessageType::Nick => {
+                            if let Content::Vec(new, _) = content {
+                                if users.remove(&from) {
+                                    users.insert(String::from_utf8_lossy(new).into_owned());
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                relayed += 1;
+                remember(&ctx, &mut history, &message);
+                webhooks.post(&ctx, &message);
+                relay(&links, &message, Some(&server)).await;
+                broadcast(&map, *message).await;
+            }
+            InternalMessage::LinkUp {
+                server,
+                sender,
+                resp,
+            } => {
+                let own = *server == ctx.config().server_name;
+                if own || links.contains_key(&server) {
+                    let _ = resp.send(false);
+                } else {
+                    info!(%server, "linked");
+                    broadcast(&map, server_notice(format!("Linked to {}.", server))).await;
+                    links.insert(server, sender);
+                    let _ = resp.send(true);
+                }
+            }
+            InternalMessage::LinkDown { server } => {
+                if links.remove(&server).is_some() {
+                    info!(%server, "unlinked");
+                    let notice = format!("Lost the link to {}.", server);
+                    broadcast(&map, server_notice(notice)).await;
+                    // nothing will tell that users who came over the link leave
+                    for user in remote_users.remove(&server).unwrap_or_default() {
+                        let header = ServerHeader::default().with_username(&user).to_json();
+                        let logout = InternalMessage::Message {
+                            desc: Descriptor::from(MessageType::Logout)
+                                .with_header_len(header.len() as u16),
+                            header: Arc::new(header),
+                            content: Content::None,
+                        };
+                        broadcast(&map, logout).await;
+                    }
+                }
+            }
+            InternalMessage::Join {
+                username,
+                addr,
+                admin,
+                resp,
+                sender,
+            } => {
+                let header = Arc::new(
+                    ServerHeader::default()
+                        .with_username(username.as_str())
+                        .to_json(),
+                );
+                if let Entry::Vacant(entry) = map.entry(username) {
+                    let motd = ctx.config().motd.clone();
+                    // the connection isn't written to yet, waiting for room would never end
+                    if let Some(motd) = motd {
+                        let _ = sender.try_send(server_notice(motd));
+                    }
+                    for msg in &history {
+                        let _ = sender.try_send(msg.try_clone().unwrap());
+                    }
+                    info!(user = %entry.key(), %addr, "joined");
+                    entry.insert(Peer {
+                        sender,
+                        addr,
+                        admin,
+                    });
+                    tx.send(InternalMessage::Message {
+                        desc: Descriptor::from(MessageType::Login)
+                            .with_header_len(header.len() as u16),
+                        header,
+                        content: Content::None,
+                    })
+                    .await
+                    .unwrap();
+                    let _ = resp.send(MessageType::Login);
+                } else {
+                    debug!(%addr, "username taken");
+                    let _ = resp.send(MessageType::UsernameExists);
+                }
+            }
+            InternalMessage::Logout { username } => {
+                info!(user = %username, "left");
+                map.remove(&username);
+                keys.remove(&username);
+                let header = Arc::new(
+                    ServerHeader::default()
+                        .with_username(username.as_str())
+                        .to_json(),
+                );
+                tx.send(InternalMessage::Message {
+                    desc: Descriptor::from(MessageType::Logout)
+                        .with_header_len(header.len() as u16),
+                    header,
+                    content: Content::None,
+                })
+                .await
+                .unwrap();
+            }
+            InternalMessage::Offer {
+                id,
+                desc,
+                header,
+                content,
+            } => {
+                let offer = InternalMessage::Message {
+                    desc: Descriptor::from(MessageType::FileOffer).with_header_len(desc.header_len),
+                    header: Arc::clone(&header),
+                    content: Content::None,
+                };
+                ctx.metrics.relayed(desc.r#type, desc.content_len);
+                let expires = now + ctx.config().offer_ttl;
+                debug!(%id, "file offered");
+                offers.insert(
+                    id,
+                    StoredOffer {
+                        desc,
+                        header,
+                        content,
+                        expires,
+                    },
+                );
+                broadcast(&map, offer).await;
+            }
+            InternalMessage::Accept { id, sender } => {
+                let msg = match offers.get(&id) {
+                    Some(offer) => InternalMessage::Message {
+                        desc: offer.desc,
+                        header: Arc::clone(&offer.header),
+                        content: offer.content.clone(),
+                    },
+                    None => no_such_file(),
+                };
+                let _ = sender.send(msg).await;
+            }
+            InternalMessage::Download { transfer, sender } => match offers.get(&transfer.id) {
+                Some(offer) => {
+                    let size = offer.desc.content_len;
+                    let header = Arc::clone(&offer.header);
+                    let content = offer.content.clone();
+                    tokio::spawn(async move {
+                        send_chunks(transfer, size, header, content, sender).await
+                    });
+                }
+                None => {
+                    let _ = sender.send(no_such_file()).await;
+                }
+            },
+            InternalMessage::PublishKey { username, key } => {
+                keys.insert(username, key);
+            }
+            InternalMessage::KeyRequest { user, sender } => match keys.get(&user) {
+                Some(key) => {
+                    let header = ServerHeader {
+                        key: Some(PublicKey {
+                            user: user.clone(),
+                            key: key.clone(),
+                        }),
+                        ..Default::default()
+                    };
+                    reply_with(&sender, MessageType::PublicKey, &header).await?;
+                }
+                None => no_such_user(&sender, user).await?,
+            },
+            InternalMessage::Moderate {
+                r#type,
+                admin,
+                moderation,
+                sender,
+            } => {
+                let user = moderation.user.clone();
+                info!(%admin, %user, action = ?r#type, "moderation");
+                match moderate(&map, &ctx, r#type, &admin, moderation).await {
+                    Ok(true) => {}
+                    Ok(false) => no_such_user(&sender, user).await?,
+                    Err(e) => {
+                        error!(error = %e, "failed to save the ban list");
+                        let notice = format!("Failed to save the ban list: {}", e);
+                        let _ = sender.send(server_notice(notice)).await;
+                    }
+                }
+            }
+            InternalMessage::Control { request, resp } => {
+                info!(?request, "control request");
+                let response = match request {
+                    ControlRequest::Users => {
+                        let mut users: Vec<_> = map
+                            .iter()
+                            .map(|(name, peer)| UserInfo {
+                                name: name.to_string(),
+                                addr: peer.addr,
+                                admin: peer.admin,
+                                muted: ctx.mutes.lock().unwrap().is_muted(name),
+                            })
+                            .collect();
+                        users.sort_by(|a, b| a.name.cmp(&b.name));
+                        ControlResponse::Users { users }
+                    }
+                    ControlRequest::Kick { user, reason } => {
+                        let moderation = Moderation {
+                            user,
+                            reason,
+                            duration_secs: None,
+                        };
+                        let kicked =
+                            moderate(&map, &ctx, MessageType::Kick, SYSTEM_USER, moderation).await;
+                        match kicked {
+                            Ok(true) => ControlResponse::Ok,
+                            Ok(false) => ControlResponse::Error {
+                                message: "no such user".into(),
+                            },
+                            Err(e) => ControlResponse::Error {
+                                message: e.to_string(),
+                            },
+                        }
+                    }
+                    ControlRequest::Announce { text } => {
+                        broadcast(&map, server_notice(text)).await;
+                        ControlResponse::Ok
+                    }
+                    ControlRequest::Stats => ControlResponse::Stats(Stats {
+                        users: map.len(),
+                        offers: offers.len(),
+                        relayed,
+                        uptime_secs: started.elapsed().as_secs(),
+                        storage: ctx.storage.report(),
+                    }),
+                    // reading the files must not hold up the other messages
+                    ControlRequest::Reload => {
+                        let ctx = Arc::clone(&ctx);
+                        tokio::spawn(async move {
+                            let response = match ctx.reload_blocking().await {
+                                Ok(()) => ControlResponse::Ok,
+                                Err(e) => ControlResponse::Error {
+                                    message: e.to_string(),
+                                },
+                            };
+                            let _ = resp.send(response);
+                        });
+                        continue;
+                    }
+                };
+                let _ = resp.send(response);
+            }
+            InternalMessage::Metrics { resp } => {
+                let mut queues: Vec<_> = map
+                    .iter()
+                    .map(|(user, peer)| {
+                        let depth = peer.sender.max_capacity() - peer.sender.capacity();
+                        (user.to_string(), depth)
+                    })
+                    .collect();
+                queues.sort();
+                let snapshot = Snapshot {
+                    offers: offers.len(),
+                    queues,
+                    storage: ctx.storage.report(),
+                };
+                let _ = resp.send(ctx.metrics.render(&snapshot));
+            }
+            InternalMessage::Direct {
+                to,
+                message,
+                sender,
+            } => match map.get(&to) {
+                Some(recipient) => {
+                    if let InternalMessage::Message { desc, .. } = &*message {
+                        ctx.metrics.relayed(desc.r#type, desc.content_len);
+                    }
+                    if recipient.sender.send(*message).await.is_err() {
+                        debug!(user = %to, "dropped direct message for a closed connection");
+                    }
+                }
+                None => no_such_user(&sender, to).await?,
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies a `Kick`, `Ban`, `Mute`, `Unban` or `Unmute` by `admin` and announces it to everyone.
+/// Returns `false` if there is no such user to apply it to. A ban list that can't be saved is
+/// an error, but the ban or unban still applies until the server is restarted.
+async fn moderate(
+    map: &HashMap<Arc<String>, Peer>,
+    ctx: &Context,
+    r#type: MessageType,
+    admin: &str,
+    moderation: Moderation,
+) -> io::Result<bool> {
+    let peer = map.get(&moderation.user);
+    let mut save = None;
+    let known = match r#type {
+        MessageType::Kick => peer.is_some(),
+        MessageType::Ban => {
+            let ban = Ban {
+                user: moderation.user.clone(),
+                ip: peer.map(|p| p.addr.ip()),
+                reason: moderation.reason.clone(),
+                by: admin.to_string(),
+                timestamp: Utc::now(),
+            };
+            let mut bans = ctx.bans.lock().unwrap();
+            bans.ban(ban);
+            save = Some(bans.to_file());
+            true
+        }
+        MessageType::Unban => {
+            let mut bans = ctx.bans.lock().unwrap();
+            let banned = bans.unban(&moderation.user);
+            save = banned.then(|| bans.to_file());
+            banned
+        }
+        MessageType::Mute => {
+            let duration = moderation.duration_secs.map(Duration::from_secs);
+            ctx.mutes.lock().unwrap().mute(&moderation.user, duration);
+            true
+        }
+        MessageType::Unmute => ctx.mutes.lock().unwrap().unmute(&moderation.user),
+        _ => unreachable!(),
+    };
+    if !known {
+        return Ok(false);
+    }
+    // the kicked or banned user's connection is closed once they get it
+    let header = ServerHeader {
+        from: admin,
+        moderation: Some(moderation),
+        ..Default::default()
+    }
+    .to_json();
+    let msg = InternalMessage::Message {
+        desc: Descriptor::from(r#type).with_header_len(header.len() as u16),
+        header: Arc::new(header),
+        content: Content::None,
+    };
+    broadcast(map, msg).await;
+    if let Some(file) = save {
+        tokio::task::spawn_blocking(move || file.write())
+            .await
+            .map_err(io::Error::other)??;
+    }
+    Ok(true)
+}
+
+/// A logged in user.
+#[derive(Debug)]
+struct Peer {
+    sender: Sender<InternalMessage>,
+    addr: SocketAddr,
+    admin: bool,
+}
+
+/// Who a connection is logged in as.
+struct User {
+    /// `/nick` changes the name while the user is logged in.
+    name: watch::Sender<Arc<String>>,
+    /// Admin rights are granted at login, to admins who present the admin token.
+    admin: bool,
+}
+
+/// Counts a broadcast message and keeps it in the history if it is text.
+fn remember(ctx: &Context, history: &mut VecDeque<InternalMessage>, msg: &InternalMessage) {
+    if let InternalMessage::Message { desc, .. } = msg {
+        ctx.metrics.relayed(desc.r#type, desc.content_len);
+        let depth = ctx.config().history_depth.min(MAX_HISTORY);
+        if desc.r#type == MessageType::Utf8 && depth > 0 {
+            history.push_back(msg.try_clone().unwrap());
+            while history.len() > depth {
+                history.pop_front();
+            }
+        }
+    }
+}
+
+/// Sends a broadcast message to every linked server but the one it came from.
+async fn relay(
+    links: &HashMap<Arc<String>, Sender<InternalMessage>>,
+    msg: &InternalMessage,
+    from: Option<&Arc<String>>,
+) {
+    for (server, sender) in links {
+        if Some(server) == from {
+            continue;
+        }
+        if sender.send(msg.try_clone().unwrap()).await.is_err() {
+            debug!(%server, "dropped message for a closed link");
+        }
+    }
+}
+
+async fn broadcast(map: &HashMap<Arc<String>, Peer>, msg: InternalMessage) {
+    for (user, peer) in map {
+        if peer.sender.send(msg.try_clone().unwrap()).await.is_err() {
+            debug!(%user, "dropped message for a closed connection");
+        }
+    }
+}
+
+#[tracing::instrument(name = "conn", skip_all, fields(peer = %addr, user))]
+async fn handle_connection<R, W>(
+    reader: R,
+    writer: W,
+    addr: SocketAddr,
+    mut sender: Sender<InternalMessage>,
+    ctx: Arc<Context>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    if ctx.bans.lock().unwrap().is_banned_ip(addr.ip()) {
+        info!("refused banned address");
+        ctx.metrics.login_failed("banned_address");
+        return send_msg(&mut writer, Descriptor::from(MessageType::Ban), None, None).await;
+    }
+
+    let (tx, mut rx) = channel(CONN_QUEUE);
+
+    let login = process_login(
+        &mut reader,
+        &mut writer,
+        &mut sender,
+        tx.clone(),
+        addr,
+        &ctx,
+    )
+    .await;
+    let (username, compression, admin) = match login {
+        Ok(login) => login,
+        Err(e) => {
+            debug!(error = %e, "disconnected before logging in");
+            return Err(e);
+        }
+    };
+    Span::current().record("user", tracing::field::display(&username));
+    info!(?compression, admin, "logged in");
+
+    let (name, me) = watch::channel(username);
+    let user = User { name, admin };
+    let mut writer_task = tokio::spawn(
+        async move {
+            // large contents are sent a chunk at a time, taking turns with each other
+            // and with any message that arrives in between
+            let mut streams = VecDeque::new();
+            loop {
+                let msg = if streams.is_empty() {
+                    match rx.recv().await {
+                        Some(msg) => msg,
+                        None => break,
+                    }
+                } else {
+                    match rx.try_recv() {
+                        Ok(msg) => msg,
+                        Err(TryRecvError::Empty) => {
+                            write_next_chunk(&mut writer, &mut streams, compression).await?;
+                            continue;
+                        }
+                        Err(TryRecvError::Disconnected) => break,
+                    }
+                };
+                match msg {
+                    InternalMessage::Message {
+                        desc,
+                        header,
+                        content,
+                    } if desc.content_len > CHUNK_SIZE as u64 => {
+                        let mut start: ServerHeader = serde_json::from_slice(&header).unwrap();
+                        let stream = Transfer {
+                            id: uuid::Uuid::new_v4().to_string(),
+                            offset: 0,
+                            size: desc.content_len,
+                            ..Default::default()
+                        };
+                        start.stream = Some(stream.clone());
+                        let header = start.to_json();
+                        writer
+                            .write_all(
+                                desc.with_header_len(header.len() as u16)
+                                    .with_content_len(0)
+                                    .as_bytes(),
+                            )
+                            .await?;
+                        writer.write_all(&header).await?;
+                        writer.flush().await?;
+                        if let Some(reader) = content.reader(0).await? {
+                            streams.push_back(OutStream { stream, reader });
+                        }
+                    }
+                    InternalMessage::Message {
+                        desc,
+                        header,
+                        content,
+                    } => {
+                        content
+                            .write(Pin::new(&mut writer), desc, &header, compression)
+                            .await?;
+                        writer.flush().await?;
+                        let removed = is_removal_of(desc, &header, &me.borrow());
+                        if removed {
+                            info!("removed by an admin");
+                            break;
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            io::Result::Ok(())
+        }
+        .instrument(Span::current()),
+    );
+
+    // the writer stops when the user is kicked or banned, which closes the connection
+    tokio::select! {
+        _ = async {
+            loop {
+                if let Err(e) = process_msg(&user, &mut reader, &mut sender, &tx, &ctx).await {
+                    if e.kind() == io::ErrorKind::UnexpectedEof {
+                        info!("disconnected");
+                    } else {
+                        warn!(error = %e, "connection failed");
+                    }
+                    break;
+                }
+            }
+        } => {}
+        written = &mut writer_task => {
+            if let Ok(Err(e)) = written {
+                warn!(error = %e, "failed to write to the connection");
+            }
+        }
+    }
+
+    let username = user.name.borrow().clone();
+    sender
+        .send(InternalMessage::Logout { username })
+        .await
+        .unwrap();
+
+    Ok(())
+}
+
+/// Whether `desc` and `header` announce that `uname` was kicked or banned.
+fn is_removal_of(desc: Descriptor, header: &[u8], uname: &str) -> bool {
+    if desc.r#type != MessageType::Kick && desc.r#type != MessageType::Ban {
+        return false;
+    }
+    serde_json::from_slice::<ServerHeader>(header)
+        .ok()
+        .and_then(|h| h.moderation)
+        .is_some_and(|m| m.user == uname)
+}
+
+/// Whether `u` may be used by a user of this server, `@` is left for users of linked servers.
+fn is_valid_username(ctx: &Context, u: &str) -> bool {
+    u != SYSTEM_USER && !u.contains('@') && !ctx.is_bot(u)
+}
+
+async fn process_login<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut BufReader<R>,
+    writer: &mut BufWriter<W>,
+    sender: &mut Sender<InternalMessage>,
+    sender_conn: Sender<InternalMessage>,
+    addr: SocketAddr,
+    ctx: &Context,
+) -> io::Result<(Arc<String>, Compression, bool)> {
+    loop {
+        let desc = Descriptor::read(Pin::new(reader)).await?;
+        if desc.r#type != MessageType::Login {
+            debug!(msg_type = ?desc.r#type, "expected a login");
+            ctx.metrics.login_failed("bad_login");
+            skip(reader, desc.header_len as u64 + desc.content_len).await?;
+            let desc = Descriptor::from(MessageType::BadLogin);
+            send_msg(writer, desc, None, None).await?;
+            continue;
+        }
+        let mut username = vec![0; desc.header_len as usize];
+        reader.read_exact(&mut username).await?;
+        let login = if desc.content_len <= MAX_LOGIN_CONTENT {
+            let mut content = vec![0; desc.content_len as usize];
+            reader.read_exact(&mut content).await?;
+            LoginInfo::from_slice(&content)
+        } else {
+            skip(reader, desc.content_len).await?;
+            LoginInfo::default()
+        };
+        let username = match String::from_utf8(username) {
+            Ok(u) if is_valid_username(ctx, &u) => Arc::new(u),
+            _ => {
+                info!("invalid username");
+                ctx.metrics.login_failed("bad_username");
+                let desc = Descriptor::from(MessageType::BadUsername);
+                send_msg(writer, desc, None, None).await?;
+                continue;
+            }
+        };
+        if ctx.bans.lock().unwrap().is_banned(&username) {
+            info!(user = %username, "refused banned user");
+            ctx.metrics.login_failed("banned");
+            send_msg(writer, Descriptor::from(MessageType::Ban), None, None).await?;
+            continue;
+        }
+        let admin = ctx.is_admin(&username, login.admin_token.as_deref());
+        if login.admin_token.is_some() && !admin {
+            warn!(user = %username, "wrong admin token");
+            ctx.metrics.login_failed("bad_admin_token");
+            send_msg(writer, Descriptor::from(MessageType::Forbidden), None, None).await?;
+            continue;
+        }
+
+        let (resp, recv) = oneshot::channel();
+        sender
+            .send(InternalMessage::Join {
+                username: Arc::clone(&username),
+                addr,
+                admin,
+                resp,
+                sender: sender_conn.clone(),
+            })
+            .await
+            .unwrap();
+        let resp = recv.await.expect("sender should not be dropped!");
+        let compression = Compression::negotiate(&login.compression, &ctx.config().compression);
+        let desc = Descriptor::from(resp).with_compression(compression);
+        send_msg(writer, desc, None, None).await?;
+        if resp != MessageType::Login {
+            info!(user = %username, reply = ?resp, "login refused");
+            ctx.metrics.login_failed("username_taken");
+        }
+        if resp == MessageType::Login {
+            break Ok((username, compression, admin));
+        }
+    }
+}
+
+async fn process_msg<R: AsyncRead + Unpin>(
+    user: &User,
+    reader: &mut BufReader<R>,
+    sender: &mut Sender<InternalMessage>,
+    conn: &Sender<InternalMessage>,
+    ctx: &Context,
+) -> io::Result<()> {
+    let name = user.name.borrow().clone();
+    let uname = name.as_str();
+    let desc = Descriptor::read(Pin::new(&mut *reader)).await?;
+    trace!(
+        msg_type = ?desc.r#type,
+        header_len = desc.header_len,
+        content_len = desc.content_len,
+        compression = ?desc.compression,
+        "message"
+    );
+    match desc.r#type {
+        MessageType::Utf8
+        | MessageType::File
+        | MessageType::Voice
+        | MessageType::Image
+        | MessageType::FileAccept
+        | MessageType::Chunk
+        | MessageType::TransferStatus
+        | MessageType::Download
+        | MessageType::Usage
+        | MessageType::PublicKey
+        | MessageType::KeyRequest
+        | MessageType::Direct
+        | MessageType::Kick
+        | MessageType::Ban
+        | MessageType::Mute
+        | MessageType::Unban
+        | MessageType::Unmute => {}
+        t => {
+            warn!(msg_type = ?t, "unexpected message");
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+    }
+    let admin_only = matches!(
+        desc.r#type,
+        MessageType::Kick
+            | MessageType::Ban
+            | MessageType::Mute
+            | MessageType::Unban
+            | MessageType::Unmute
+            | MessageType::Usage
+    );
+    if admin_only && !user.admin {
+        warn!(msg_type = ?desc.r#type, "forbidden for non-admins");
+        skip(reader, desc.header_len as u64 + desc.content_len).await?;
+        return reply(conn, uname, MessageType::Forbidden).await;
+    }
+    // TODO make it use object pool
+    let mut raw_header = vec![0; desc.header_len as usize];
+    reader.read_exact(&mut raw_header).await?;
+
+    // text that may be a command is read whole
+    let text = desc.r#type == MessageType::Utf8 && !ctx.commands.is_empty();
+    if desc.compression == Compression::None && text && desc.content_len <= BUF_SIZE as u64 {
+        let mut content = vec![0; desc.content_len as usize];
+        reader.read_exact(&mut content).await?;
+        return process_text(user, desc, &raw_header, content, sender, conn, ctx).await;
+    }
+    if desc.compression == Compression::None && text {
+        // longer text is only read whole if it is a command
+        let mut start = [0; 2];
+        reader.read_exact(&mut start).await?;
+        if start[0] != b'/' || start == *b"//" {
+            let escaped = usize::from(start == *b"//");
+            let desc = desc.with_content_len(desc.content_len - escaped as u64);
+            let content = &mut (&start[escaped..]).chain(&mut *reader);
+            return process_content(uname, desc, &raw_header, content, sender, conn, ctx).await;
+        }
+        if desc.content_len > compression::MAX_LEN as u64 {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let mut content = vec![0; desc.content_len as usize];
+        content[..2].copy_from_slice(&start);
+        reader.read_exact(&mut content[2..]).await?;
+        return process_text(user, desc, &raw_header, content, sender, conn, ctx).await;
+    }
+    if desc.compression == Compression::None {
+        return process_content(uname, desc, &raw_header, reader, sender, conn, ctx).await;
+    }
+    if desc.content_len > compression::MAX_LEN as u64 {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    let mut compressed = vec![0; desc.content_len as usize];
+    reader.read_exact(&mut compressed).await?;
+    let content = desc.compression.decompress(&compressed)?;
+    let desc = desc
+        .with_compression(Compression::None)
+        .with_content_len(content.len() as u64);
+    if text {
+        return process_text(user, desc, &raw_header, content, sender, conn, ctx).await;
+    }
+    process_content(
+        uname,
+        desc,
+        &raw_header,
+        &mut content.as_slice(),
+        sender,
+        conn,
+        ctx,
+    )
+    .await
+}
+
+/// Runs a command if `content` is text starting with a slash, otherwise handles it as any
+/// other message. A double slash sends the text with one slash less.
+async fn process_text(
+    user: &User,
+    desc: Descriptor,
+    raw_header: &[u8],
+    content: Vec<u8>,
+    sender: &mut Sender<InternalMessage>,
+    conn: &Sender<InternalMessage>,
+    ctx: &Context,
+) -> io::Result<()> {
+    let uname = user.name.borrow().clone();
+    let line = std::str::from_utf8(&content).unwrap_or_default();
+    if line.starts_with("//") {
+        let desc = desc.with_content_len(desc.content_len - 1);
+        let content = &mut &content[1..];
+        return process_content(&uname, desc, raw_header, content, sender, conn, ctx).await;
+    }
+    if let Some(line) = line.strip_prefix('/') {
+        let cx = CommandContext {
+            name: &user.name,
+            admin: user.admin,
+            conn,
+            server: sender,
+            ctx,
+        };
+        return commands::run(line, &cx).await;
+    }
+    let content = &mut content.as_slice();
+    process_content(&uname, desc, raw_header, content, sender, conn, ctx).await
+}
+
+/// Handles a message whose descriptor and header are already read, `reader` yields its uncompressed content.
+async fn process_content<R: AsyncRead + Unpin>(
+    uname: &str,
+    desc: Descriptor,
+    raw_header: &[u8],
+    reader: &mut R,
+    sender: &mut Sender<InternalMessage>,
+    conn: &Sender<InternalMessage>,
+    ctx: &Context,
+) -> io::Result<()> {
+    let speaks = matches!(
+        desc.r#type,
+        MessageType::Utf8
+            | MessageType::File
+            | MessageType::Image
+            | MessageType::Voice
+            | MessageType::Direct
+            | MessageType::TransferStatus
+            | MessageType::Chunk
+    );
+    if speaks && ctx.mutes.lock().unwrap().is_muted(uname) {
+        debug!(msg_type = ?desc.r#type, "dropped message of a muted user");
+        skip(reader, desc.content_len).await?;
+        // uploads are refused when they start, later chunks are dropped
+        if desc.r#type == MessageType::Chunk {
+            return Ok(());
+        }
+        let transfer = if desc.r#type == MessageType::TransferStatus {
+            serde_json::from_slice(raw_header).ok()
+        } else {
+            None
+        };
+        let header = ServerHeader {
+            from: uname,
+            transfer,
+            ..Default::default()
+        }
+        .to_json();
+        return reply_with_content(
+            conn,
+            MessageType::Forbidden,
+            &header,
+            "you are muted".into(),
+        )
+        .await;
+    }
+
+    match desc.r#type {
+        MessageType::FileAccept => {
+            skip(reader, desc.content_len).await?;
+            sender
+                .send(InternalMessage::Accept {
+                    id: String::from_utf8_lossy(raw_header).into_owned(),
+                    sender: conn.clone(),
+                })
+                .await
+                .unwrap();
+            return Ok(());
+        }
+        MessageType::Download => {
+            skip(reader, desc.content_len).await?;
+            if let Ok(transfer) = serde_json::from_slice(raw_header) {
+                sender
+                    .send(InternalMessage::Download {
+                        transfer,
+                        sender: conn.clone(),
+                    })
+                    .await
+                    .unwrap();
+            }
+            return Ok(());
+        }
+        MessageType::TransferStatus => {
+            skip(reader, desc.content_len).await?;
+            if let Ok(mut transfer) = serde_json::from_slice::<Transfer>(raw_header) {
+                transfer.offset = match ctx.storage.partial_path(uname, &transfer.id) {
+                    Some(path) => partial_len(&path).await,
+                    None => 0,
+                };
+                let remaining = transfer.size.saturating_sub(transfer.offset);
+                let fits = ctx
+                    .check_upload(transfer.size)
+                    .and_then(|_| ctx.storage.check(uname, remaining));
+                if let Err(e) = fits {
+                    return reply_quota(conn, uname, e, Some(transfer)).await;
+                }
+                return reply_transfer(conn, uname, MessageType::TransferStatus, transfer).await;
+            }
+            return Ok(());
+        }
+        MessageType::Chunk => {
+            return process_chunk(uname, desc, raw_header, reader, sender, conn, ctx).await;
+        }
+        MessageType::PublicKey => {
+            skip(reader, desc.content_len).await?;
+            match serde_json::from_slice::<PublicKey>(raw_header) {
+                Ok(key) if crypto::is_public_key(&key.key) => {
+                    sender
+                        .send(InternalMessage::PublishKey {
+                            username: Arc::new(uname.to_string()),
+                            key: key.key,
+                        })
+                        .await
+                        .unwrap();
+                }
+                _ => return reply(conn, uname, MessageType::BadDirect).await,
+            }
+            return Ok(());
+        }
+        MessageType::KeyRequest => {
+            skip(reader, desc.content_len).await?;
+            sender
+                .send(InternalMessage::KeyRequest {
+                    user: String::from_utf8_lossy(raw_header).into_owned(),
+                    sender: conn.clone(),
+                })
+                .await
+                .unwrap();
+            return Ok(());
+        }
+        MessageType::Kick
+        | MessageType::Ban
+        | MessageType::Mute
+        | MessageType::Unban
+        | MessageType::Unmute => {
+            skip(reader, desc.content_len).await?;
+            if let Ok(moderation) = serde_json::from_slice(raw_header) {
+                sender
+                    .send(InternalMessage::Moderate {
+                        r#type: desc.r#type,
+                        admin: Arc::new(uname.to_string()),
+                        moderation,
+                        sender: conn.clone(),
+                    })
+                    .await
+                    .unwrap();
+            }
+            return Ok(());
+        }
+        MessageType::Usage => {
+            skip(reader, desc.content_len).await?;
+            let report = serde_json::to_vec(&ctx.storage.report()).unwrap();
+            let header = ServerHeader::default().with_username(uname).to_json();
+            return reply_with_content(conn, MessageType::Usage, &header, report).await;
+        }
+        _ => {}
+    }
+
+    let upload = matches!(
+        desc.r#type,
+        MessageType::File | MessageType::Image | MessageType::Voice
+    );
+    if upload {
+        if let Err(e) = ctx.check_upload(desc.content_len) {
+            skip(reader, desc.content_len).await?;
+            return reply_quota(conn, uname, e, None).await;
+        }
+    }
+
+    // files are always kept on disk since they stay around until the offer expires
+    let spool = desc.r#type == MessageType::File || desc.content_len > BUF_SIZE as u64;
+    if spool {
+        if let Err(e) = ctx.storage.reserve(uname, desc.content_len) {
+            skip(reader, desc.content_len).await?;
+            return reply_quota(conn, uname, e, None).await;
+        }
+    }
+    let owner = spool.then_some((uname, &ctx.storage));
+    let (content, hash) = read_content(reader, desc.content_len, owner).await?;
+
+    let filename = if desc.r#type == MessageType::File {
+        Some(String::from_utf8_lossy(raw_header).into_owned())
+    } else {
+        None
+    };
+
+    let image = if desc.r#type == MessageType::Image {
+        match serde_json::from_slice::<ImageInfo>(raw_header) {
+            Ok(info) if info.validate(&content.magic().await?) => Some(info),
+            Ok(info) => return reply_rejected(conn, uname, MessageType::BadImage, info.hash).await,
+            Err(_) => return reply(conn, uname, MessageType::BadImage).await,
+        }
+    } else {
+        None
+    };
+
+    let voice = if desc.r#type == MessageType::Voice {
+        match serde_json::from_slice::<VoiceInfo>(raw_header) {
+            Ok(info) if info.validate(&content.magic().await?) => Some(info),
+            Ok(info) => return reply_rejected(conn, uname, MessageType::BadVoice, info.hash).await,
+            Err(_) => return reply(conn, uname, MessageType::BadVoice).await,
+        }
+    } else {
+        None
+    };
+
+    let direct = if desc.r#type == MessageType::Direct {
+        match serde_json::from_slice::<Direct>(raw_header) {
+            Ok(direct) if crypto::is_public_key(&direct.key) => Some(direct),
+            _ => return reply(conn, uname, MessageType::BadDirect).await,
+        }
+    } else {
+        None
+    };
+
+    if let Some(filename) = filename {
+        return offer_file(uname, &filename, desc.content_len, hash, content, sender).await;
+    }
+
+    let declared = image
+        .as_ref()
+        .map(|i| &i.hash)
+        .or_else(|| voice.as_ref().map(|v| &v.hash));
+    if let Some(declared) = declared {
+        if *declared != hash {
+            let transfer = Transfer {
+                hash: Some(declared.clone()),
+                ..Default::default()
+            };
+            info!("checksum mismatch");
+            return reply_transfer(conn, uname, MessageType::ChecksumMismatch, transfer).await;
+        }
+    }
+
+    let to = direct.as_ref().map(|d| d.to.clone());
+    let header = ServerHeader {
+        timestamp: Utc::now(),
+        from: uname,
+        image,
+        voice,
+        direct,
+        ..Default::default()
+    };
+
+    let header = Arc::new(serde_json::to_vec(&header).unwrap());
+    let message = InternalMessage::Message {
+        desc: desc.with_header_len(header.len() as u16),
+        header,
+        content,
+    };
+    let message = match to {
+        Some(to) => InternalMessage::Direct {
+            to,
+            message: Box::new(message),
+            sender: conn.clone(),
+        },
+        None => message,
+    };
+    sender.send(message).await.unwrap();
+
+    Ok(())
+}
+
+/// Keeps a received file on the server and lets everyone know it can be downloaded.
+async fn offer_file(
+    uname: &str,
+    filename: &str,
+    size: u64,
+    hash: String,
+    content: Content,
+    sender: &mut Sender<InternalMessage>,
+) -> io::Result<()> {
+    let id = uuid::Uuid::new_v4().to_string();
+    let header = ServerHeader {
+        timestamp: Utc::now(),
+        from: uname,
+        filename: Some(filename),
+        offer: Some(FileOffer {
+            id: id.clone(),
+            size,
+            hash,
+        }),
+        ..Default::default()
+    };
+    let header = Arc::new(serde_json::to_vec(&header).unwrap());
+    sender
+        .send(InternalMessage::Offer {
+            id,
+            desc: Descriptor::from(MessageType::File)
+                .with_header_len(header.len() as u16)
+                .with_content_len(size),
+            header,
+            content,
+        })
+        .await
+        .unwrap();
+    Ok(())
+}
+
+/// Appends a `Chunk` of an upload to its partial spool file. Once the last chunk
+/// arrives and the checksum matches, the file is offered like a regular `File`,
+/// or relayed as an `Image` or `Voice` message if the transfer describes one.
+async fn process_chunk<R: AsyncRead + Unpin>(
+    uname: &str,
+    desc: Descriptor,
+    raw_header: &[u8],
+    reader: &mut R,
+    sender: &mut Sender<InternalMessage>,
+    conn: &Sender<InternalMessage>,
+    ctx: &Context,
+) -> io::Result<()> {
+    let transfer = match serde_json::from_slice::<Transfer>(raw_header) {
+        Ok(transfer) => transfer,
+        Err(_) => return skip(reader, desc.content_len).await,
+    };
+    let path = match ctx.storage.partial_path(uname, &transfer.id) {
+        Some(path) => path,
+        None => return skip(reader, desc.content_len).await,
+    };
+
+    if let Err(e) = ctx.check_upload(transfer.size) {
+        skip(reader, desc.content_len).await?;
+        return reply_quota(conn, uname, e, Some(transfer)).await;
+    }
+
+    let stored = partial_len(&path).await;
+    // a chunk must continue the partial file and not run past the announced size
+    let oversized = desc.content_len > CHUNK_SIZE as u64
+        || transfer.offset.saturating_add(desc.content_len) > transfer.size;
+    if transfer.offset != stored || oversized {
+        skip(reader, desc.content_len).await?;
+        let status = Transfer {
+            offset: stored,
+            ..transfer
+        };
+        return reply_transfer(conn, uname, MessageType::TransferStatus, status).await;
+    }
+
+    if let Err(e) = ctx.storage.reserve(uname, desc.content_len) {
+        skip(reader, desc.content_len).await?;
+        return reply_quota(conn, uname, e, Some(transfer)).await;
+    }
+    if let Err(e) = append_chunk(&path, reader, desc.content_len).await {
+        // only whole chunks are kept, so the upload can be resumed after them
+        if let Ok(file) = OpenOptions::new().write(true).open(&path).await {
+            let _ = file.set_len(stored).await;
+        }
+        ctx.storage.release(uname, desc.content_len);
+        return Err(e);
+    }
+    if !transfer.is_last(desc.content_len) {
+        return Ok(());
+    }
+
+    // the partial file was reserved chunk by chunk, from now on the spool file owns it
+    let size = partial_len(&path).await;
+    let hash = sha256_file(&path).await?;
+    if transfer.hash.as_ref() != Some(&hash) {
+        let _ = tokio::fs::remove_file(&path).await;
+        ctx.storage.release(uname, size);
+        info!(id = %transfer.id, "checksum mismatch");
+        return reply_transfer(conn, uname, MessageType::ChecksumMismatch, transfer).await;
+    }
+    let spool = spool_path(ctx.storage.dir());
+    tokio::fs::rename(&path, &spool).await?;
+    let spool = SpoolFile::new(spool, uname, size, Arc::clone(&ctx.storage));
+    if transfer.image.is_some() || transfer.voice.is_some() {
+        let content = Content::file(spool);
+        return relay_media(uname, transfer, size, hash, content, sender, conn).await;
+    }
+    let filename = transfer.filename.unwrap_or_default();
+    offer_file(uname, &filename, size, hash, Content::file(spool), sender).await
+}
+
+async fn append_chunk<R: AsyncRead + Unpin>(
+    path: &Path,
+    reader: &mut R,
+    len: u64,
+) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    let copied = io::copy(&mut reader.take(len), &mut file).await?;
+    file.flush().await?;
+    if copied != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Checks a completed image or voice note upload like one sent in a single message and relays it.
+async fn relay_media(
+    uname: &str,
+    transfer: Transfer,
+    size: u64,
+    hash: String,
+    content: Content,
+    sender: &mut Sender<InternalMessage>,
+    conn: &Sender<InternalMessage>,
+) -> io::Result<()> {
+    let magic = content.magic().await?;
+    let (t, bad, valid) = match (&transfer.image, &transfer.voice) {
+        (Some(image), _) => (
+            MessageType::Image,
+            MessageType::BadImage,
+            image.validate(&magic) && image.hash == hash,
+        ),
+        (None, Some(voice)) => (
+            MessageType::Voice,
+            MessageType::BadVoice,
+            voice.validate(&magic) && voice.hash == hash,
+        ),
+        (None, None) => unreachable!(),
+    };
+    if !valid {
+        return reply_rejected(conn, uname, bad, hash).await;
+    }
+    let header = ServerHeader {
+        timestamp: Utc::now(),
+        from: uname,
+        image: transfer.image,
+        voice: transfer.voice,
+        ..Default::default()
+    };
+    let header = Arc::new(serde_json::to_vec(&header).unwrap());
+    sender
+        .send(InternalMessage::Message {
+            desc: Descriptor::from(t)
+                .with_header_len(header.len() as u16)
+                .with_content_len(size),
+            header,
+            content,
+        })
+        .await
+        .unwrap();
+    Ok(())
+}
+
+/// Streams an offered file to a single connection as `Chunk` messages, starting at `transfer.offset`.
+async fn send_chunks(
+    transfer: Transfer,
+    size: u64,
+    header: Arc<Vec<u8>>,
+    content: Content,
+    sender: Sender<InternalMessage>,
+) -> io::Result<()> {
+    let offer: ServerHeader = serde_json::from_slice(&header).unwrap();
+    let mut offset = transfer.offset.min(size);
+    let mut reader = match content.reader(offset).await? {
+        Some(reader) => reader,
+        None => return Ok(()),
+    };
+    loop {
+        let len = (size - offset).min(CHUNK_SIZE as u64);
+        let mut buf = vec![0; len as usize];
+        reader.read_exact(&mut buf).await?;
+        let chunk = Transfer {
+            id: transfer.id.clone(),
+            offset,
+            size,
+            filename: offer.filename.map(String::from),
+            hash: offer
+                .offer
+                .as_ref()
+                .filter(|_| offset + len >= size)
+                .map(|o| o.hash.clone()),
+            ..Default::default()
+        };
+        let header = Arc::new(
+            serde_json::to_vec(&ServerHeader {
+                from: offer.from,
+                transfer: Some(chunk),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let msg = InternalMessage::Message {
+            desc: Descriptor::from(MessageType::Chunk)
+                .with_header_len(header.len() as u16)
+                .with_content_len(len),
+            header,
+            content: Content::vec(buf),
+        };
+        if sender.send(msg).await.is_err() {
+            break;
+        }
+        offset += len;
+        if offset >= size {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Content of a message that is being sent in chunks.
+struct OutStream {
+    stream: Transfer,
+    reader: Pin<Box<dyn AsyncRead + Send>>,
+}
+
+/// Writes the next chunk of the first stream and moves it to the back of the queue.
+async fn write_next_chunk<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    streams: &mut VecDeque<OutStream>,
+    compression: Compression,
+) -> io::Result<()> {
+    let mut out = match streams.pop_front() {
+        Some(out) => out,
+        None => return Ok(()),
+    };
+    let len = (out.stream.size - out.stream.offset).min(CHUNK_SIZE as u64);
+    let mut buf = vec![0; len as usize];
+    out.reader.read_exact(&mut buf).await?;
+    let header = ServerHeader {
+        transfer: Some(out.stream.clone()),
+        ..Default::default()
+    }
+    .to_json();
+    let desc = Descriptor::from(MessageType::Chunk)
+        .with_header_len(header.len() as u16)
+        .with_content_len(len);
+    Content::vec(buf)
+        .write(Pin::new(&mut *writer), desc, &header, compression)
+        .await?;
+    writer.flush().await?;
+    out.stream.offset += len;
+    if out.stream.offset < out.stream.size {
+        streams.push_back(out);
+    }
+    Ok(())
+}
+
+fn spool_path(dir: &Path) -> PathBuf {
+    dir.join(uuid::Uuid::new_v4().to_string())
+}
+
+async fn partial_len(path: &Path) -> u64 {
+    tokio::fs::metadata(path)
+        .await
+        .map(|m| m.len())
+        .unwrap_or(0)
+}
+
+async fn skip<R: AsyncRead + Unpin>(reader: &mut R, len: u64) -> io::Result<()> {
+    io::copy(&mut reader.take(len), &mut io::sink()).await?;
+    Ok(())
+}
+
+/// A `ServerNotice` from [`SYSTEM_USER`] with `text` as content.
+fn server_notice(text: String) -> InternalMessage {
+    let header = ServerHeader::default().with_username(SYSTEM_USER).to_json();
+    InternalMessage::Message {
+        desc: Descriptor::from(MessageType::ServerNotice)
+            .with_header_len(header.len() as u16)
+            .with_content_len(text.len() as u64),
+        header: Arc::new(header),
+        content: Content::vec(text.into_bytes()),
+    }
+}
+
+fn no_such_file() -> InternalMessage {
+    let header = Arc::new(ServerHeader::default().to_json());
+    InternalMessage::Message {
+        desc: Descriptor::from(MessageType::NoSuchFile).with_header_len(header.len() as u16),
+        header,
+        content: Content::None,
+    }
+}
+
+/// Reads `len` bytes of content and returns them along with their hex encoded SHA-256.
+///
+/// With an `owner` the content is spooled to disk, `len` bytes must be already reserved for them.
+async fn read_content<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    len: u64,
+    owner: Option<(&str, &Arc<Storage>)>,
+) -> io::Result<(Content, String)> {
+    let mut hasher = Sha256::new();
+    let content = if let Some((owner, storage)) = owner {
+        let spool = SpoolFile::new(spool_path(storage.dir()), owner, len, Arc::clone(storage));
+        let mut writer = BufWriter::new(File::create(spool.path()).await?);
+        let mut reader = reader.take(len);
+        let mut buf = vec![0; BUF_SIZE];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            writer.write_all(&buf[..n]).await?;
+        }
+        writer.flush().await?;
+        if reader.limit() != 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Content::file(spool)
+    } else {
+        // TODO make it use object pool
+        let mut buf = vec![0; len as usize];
+        reader.read_exact(&mut buf).await?;
+        hasher.update(&buf);
+        Content::vec(buf)
+    };
+    Ok((content, format!("{:x}", hasher.finalize())))
+}
+
+async fn no_such_user(conn: &Sender<InternalMessage>, user: String) -> io::Result<()> {
+    let header = ServerHeader {
+        to: Some(user),
+        ..Default::default()
+    };
+    reply_with(conn, MessageType::NoSuchUser, &header).await
+}
+
+/// Sends a header-only message of type `t` to a single connection.
+async fn reply(conn: &Sender<InternalMessage>, uname: &str, t: MessageType) -> io::Result<()> {
+    reply_with(conn, t, ServerHeader::default().with_username(uname)).await
+}
+
+async fn reply_transfer(
+    conn: &Sender<InternalMessage>,
+    uname: &str,
+    t: MessageType,
+    transfer: Transfer,
+) -> io::Result<()> {
+    let header = ServerHeader {
+        from: uname,
+        transfer: Some(transfer),
+        ..Default::default()
+    };
+    reply_with(conn, t, &header).await
+}
+
+/// Refuses an upload, `hash` lets the sender tell which one.
+async fn reply_rejected(
+    conn: &Sender<InternalMessage>,
+    uname: &str,
+    t: MessageType,
+    hash: String,
+) -> io::Result<()> {
+    let transfer = Transfer {
+        hash: Some(hash),
+        ..Default::default()
+    };
+    reply_transfer(conn, uname, t, transfer).await
+}
+
+async fn reply_quota(
+    conn: &Sender<InternalMessage>,
+    uname: &str,
+    e: QuotaError,
+    transfer: Option<Transfer>,
+) -> io::Result<()> {
+    let header = ServerHeader {
+        from: uname,
+        transfer,
+        ..Default::default()
+    }
+    .to_json();
+    let reason = match e {
+        QuotaError::User => "user quota exceeded",
+        QuotaError::Global => "server storage quota exceeded",
+        QuotaError::Disk => "not enough disk space on the server",
+        QuotaError::TooLarge => "file is too large",
+    };
+    info!(reason, "upload refused");
+    reply_with_content(conn, MessageType::QuotaExceeded, &header, reason.into()).await
+}
+
+async fn reply_with(
+    conn: &Sender<InternalMessage>,
+    t: MessageType,
+    header: &ServerHeader<'_, '_>,
+) -> io::Result<()> {
+    reply_with_content(conn, t, &header.to_json(), Vec::new()).await
+}
+
+async fn reply_with_content(
+    conn: &Sender<InternalMessage>,
+    t: MessageType,
+    header: &[u8],
+    content: Vec<u8>,
+) -> io::Result<()> {
+    let _ = conn
+        .send(InternalMessage::Message {
+            desc: Descriptor::from(t)
+                .with_header_len(header.len() as u16)
+                .with_content_len(content.len() as u64),
+            header: Arc::new(header.to_vec()),
+            content: if content.is_empty() {
+                Content::None
+            } else {
+                Content::vec(content)
+            },
+        })
+        .await;
+    Ok(())
+}
+
+/// Reads a whole message that `handle_connection` wrote to a gateway's pipe, returning its
+/// descriptor and the header followed by the content. `None` once the connection is closed.
+async fn read_piped_msg<R: AsyncRead + Unpin>(
+    pipe: &mut R,
+) -> io::Result<Option<(Descriptor, Vec<u8>)>> {
+    let desc = match Descriptor::read(Pin::new(&mut *pipe)).await {
+        Ok(desc) => desc,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let len = desc.header_len as u64 + desc.content_len;
+    if len > MAX_PIPED_MSG {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    let mut rest = vec![0; len as usize];
+    pipe.read_exact(&mut rest).await?;
+    Ok(Some((desc, rest)))
+}
+
+async fn send_msg<W: AsyncWrite + Unpin>(
+    writer: &mut BufWriter<W>,
+    desc: Descriptor,
+    header: Option<Arc<Vec<u8>>>,
+    content: Option<Arc<Vec<u8>>>,
+) -> io::Result<()> {
+    writer.write_all(desc.as_bytes()).await?;
+    if let Some(header) = header {
+        writer.write_all(header.as_slice()).await?;
+    }
+    if let Some(content) = content {
+        writer.write_all(content.as_slice()).await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+impl Content {
+    fn vec(content: Vec<u8>) -> Self {
+        Content::Vec(Arc::new(content), Arc::default())
+    }
+
+    fn file(spool: SpoolFile) -> Self {
+        Content::File(Arc::new(spool), Arc::default())
+    }
+
+    /// Returns the content compressed with `compression`, compressing it on a blocking
+    /// thread if no other recipient did yet. `None` if it is sent uncompressed.
+    async fn compressed(&self, compression: Compression) -> io::Result<Option<Arc<Vec<u8>>>> {
+        let cache = match self {
+            Content::Vec(_, cache) | Content::File(_, cache) => cache,
+            Content::None => return Ok(None),
+        };
+        let cell = match cache.get(compression) {
+            Some(cell) => cell,
+            None => return Ok(None),
+        };
+        let compressed = cell
+            .get_or_try_init(|| async {
+                let content = match self {
+                    Content::Vec(v, _) => Arc::clone(v),
+                    Content::File(file, _) => Arc::new(tokio::fs::read(file.path()).await?),
+                    Content::None => unreachable!(),
+                };
+                tokio::task::spawn_blocking(move || compression.compress(&content).map(Arc::new))
+                    .await
+                    .map_err(io::Error::other)
+            })
+            .await?;
+        Ok(compressed.clone())
+    }
+
+    /// Writes a whole message with this content, compressed if it is worth it.
+    async fn write<W: AsyncWriteExt>(
+        self,
+        mut writer: Pin<&mut W>,
+        desc: Descriptor,
+        header: &[u8],
+        compression: Compression,
+    ) -> io::Result<()> {
+        let worth_trying = compression != Compression::None
+            && (compression::MIN_LEN..=compression::MAX_LEN).contains(&(desc.content_len as usize));
+        let compressed = if worth_trying {
+            self.compressed(compression).await?
+        } else {
+            None
+        };
+        if let Some(compressed) = compressed {
+            let desc = desc
+                .with_compression(compression)
+                .with_content_len(compressed.len() as u64);
+            writer.write_all(desc.as_bytes()).await?;
+            writer.write_all(header).await?;
+            return writer.write_all(&compressed).await;
+        }
+
+        writer.write_all(desc.as_bytes()).await?;
+        writer.write_all(header).await?;
+        match self {
+            Content::Vec(v, _) => {
+                writer.write_all(v.as_slice()).await?;
+            }
+            Content::File(path, _) => {
+                // TODO make it use object pool
+                let mut buf = Vec::with_capacity(BUF_SIZE);
+                let mut reader = BufReader::new(File::open(path.path()).await?);
+                while reader.read_buf(&mut buf).await? != 0 {
+                    writer.write_all(&buf).await?;
+                    buf.clear();
+                }
+            }
+            Content::None => {}
+        }
+        Ok(())
+    }
+
+    /// Opens the content for reading from `offset`.
+    async fn reader(&self, offset: u64) -> io::Result<Option<Pin<Box<dyn AsyncRead + Send>>>> {
+        Ok(match self {
+            Content::Vec(v, _) => {
+                let start = (offset as usize).min(v.len());
+                Some(Box::pin(Cursor::new(v[start..].to_vec())))
+            }
+            Content::File(path, _) => {
+                let mut file = File::open(path.path()).await?;
+                file.seek(SeekFrom::Start(offset)).await?;
+                Some(Box::pin(BufReader::new(file)))
+            }
+            Content::None => None,
+        })
+    }
+
+    /// Returns up to the first 16 bytes of the content, enough to sniff its format.
+    async fn magic(&self) -> io::Result<Vec<u8>> {
+        const MAGIC_LEN: usize = 16;
+        match self {
+            Content::Vec(v, _) => Ok(v[..v.len().min(MAGIC_LEN)].to_vec()),
+            Content::File(path, _) => {
+                let mut buf = Vec::with_capacity(MAGIC_LEN);
+                File::open(path.path())
+                    .await?
+                    .take(MAGIC_LEN as u64)
+                    .read_to_end(&mut buf)
+                    .await?;
+                Ok(buf)
+            }
+            Content::None => Ok(Vec::new()),
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Banned users and addresses, kept in a JSON file so they survive restarts.
+#[derive(Debug)]
+pub struct BanList {
+    path: PathBuf,
+    bans: Vec<Ban>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub user: String,
+    /// Address the user was connected from when banned.
+    pub ip: Option<IpAddr>,
+    pub reason: Option<String>,
+    pub by: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl BanList {
+    /// Loads the list from `path`, a missing file is an empty list.
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let bans = match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self { path, bans })
+    }
+
+    /// Reads the list from its file again, picking up edits made by hand.
+    pub fn reload(&mut self) -> io::Result<()> {
+        *self = Self::load(self.path.clone())?;
+        Ok(())
+    }
+
+    /// The list as it should be written to its file. Writing is left to the caller,
+    /// so it can be done without holding the list locked.
+    pub fn to_file(&self) -> BanFile {
+        BanFile {
+            path: self.path.clone(),
+            json: serde_json::to_vec_pretty(&self.bans).unwrap(),
+        }
+    }
+
+    pub fn is_banned(&self, user: &str) -> bool {
+        self.bans.iter().any(|b| b.user == user)
+    }
+
+    pub fn is_banned_ip(&self, ip: IpAddr) -> bool {
+        self.bans.iter().any(|b| b.ip == Some(ip))
+    }
+
+    /// Bans a user, the list has to be saved with [`BanList::to_file`] afterwards.
+    pub fn ban(&mut self, ban: Ban) {
+        self.bans.retain(|b| b.user != ban.user);
+        self.bans.push(ban);
+    }
+
+    /// Lifts the ban of `user` and of the address they were banned from, returns
+    /// whether they were banned at all. The list has to be saved like after [`BanList::ban`].
+    pub fn unban(&mut self, user: &str) -> bool {
+        let len = self.bans.len();
+        self.bans.retain(|b| b.user != user);
+        self.bans.len() != len
+    }
+}
+
+/// A snapshot of a [`BanList`] to write to its file.
+#[derive(Debug)]
+pub struct BanFile {
+    path: PathBuf,
+    json: Vec<u8>,
+}
+
+impl BanFile {
+    pub fn write(self) -> io::Result<()> {
+        std::fs::write(&self.path, self.json)
+    }
+}
+
+/// Users who may not send messages, until the given instant or indefinitely.
+#[derive(Debug, Default)]
+pub struct Mutes(HashMap<String, Option<Instant>>);
+
+impl Mutes {
+    /// Mutes `user` for `duration`, or indefinitely if there is none or it is too long to count.
+    pub fn mute(&mut self, user: &str, duration: Option<Duration>) {
+        let until = duration.and_then(|d| Instant::now().checked_add(d));
+        self.0.insert(user.to_string(), until);
+    }
+
+    pub fn unmute(&mut self, user: &str) -> bool {
+        self.0.remove(user).is_some()
+    }
+
+    pub fn is_muted(&mut self, user: &str) -> bool {
+        match self.0.get(user) {
+            Some(Some(until)) if *until <= Instant::now() => {
+                self.0.remove(user);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::UsageReport;
+
+/// How long a measurement of the free disk space is trusted.
+const FREE_SPACE_TTL: Duration = Duration::from_secs(1);
+
+/// Keeps track of how much of the spool directory every user occupies.
+#[derive(Debug)]
+pub struct Storage {
+    dir: PathBuf,
+    limits: Mutex<Limits>,
+    usage: Mutex<Usage>,
+    /// Free disk space when it was last measured, lowered by what was reserved since.
+    free: Mutex<Option<(Instant, u64)>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    user_quota: Option<u64>,
+    global_quota: Option<u64>,
+    min_free_space: u64,
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    total: u64,
+    by_user: HashMap<String, u64>,
+}
+
+/// Why an upload can't be stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaError {
+    User,
+    Global,
+    Disk,
+    /// The upload is larger than the server accepts at all.
+    TooLarge,
+}
+
+impl Storage {
+    pub fn new(
+        dir: PathBuf,
+        user_quota: Option<u64>,
+        global_quota: Option<u64>,
+        min_free_space: u64,
+    ) -> Self {
+        Self {
+            dir,
+            limits: Mutex::new(Limits {
+                user_quota,
+                global_quota,
+                min_free_space,
+            }),
+            usage: Mutex::default(),
+            free: Mutex::default(),
+        }
+    }
+
+    /// Changes the quotas, uploads already stored are kept even if they no longer fit.
+    pub fn set_limits(
+        &self,
+        user_quota: Option<u64>,
+        global_quota: Option<u64>,
+        min_free_space: u64,
+    ) {
+        *self.limits.lock().unwrap() = Limits {
+            user_quota,
+            global_quota,
+            min_free_space,
+        };
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Where the partial upload `id` of `owner` is kept. The id is chosen by the client and
+    /// must be a hex SHA-256, so it can't be used to reach outside the spool directory.
+    pub fn partial_path(&self, owner: &str, id: &str) -> Option<PathBuf> {
+        if id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            let owner: String = owner.bytes().map(|b| format!("{:02x}", b)).collect();
+            Some(self.dir.join(format!("{}.{}.part", id, owner)))
+        } else {
+            None
+        }
+    }
+
+    /// Accounts the partial uploads found in the spool directory to their owners. Other spool
+    /// files belong to offers of an earlier run, which can't be downloaded anymore, and are removed.
+    pub fn rebuild(&self) -> io::Result<()> {
+        let mut usage = Usage::default();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            match partial_owner(&name) {
+                Some(owner) => {
+                    let size = entry.metadata()?.len();
+                    usage.total += size;
+                    *usage.by_user.entry(owner).or_default() += size;
+                }
+                None if uuid::Uuid::parse_str(&name).is_ok() => {
+                    fs::remove_file(entry.path())?;
+                }
+                None => {}
+            }
+        }
+        *self.usage.lock().unwrap() = usage;
+        Ok(())
+    }
+
+    /// Removes partial uploads nothing was appended to for `ttl` and releases their space.
+    pub fn expire_partials(&self, ttl: Duration) -> io::Result<()> {
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let owner = match partial_owner(&entry.file_name().to_string_lossy()) {
+                Some(owner) => owner,
+                None => continue,
+            };
+            let metadata = entry.metadata()?;
+            let idle = metadata
+                .modified()
+                .ok()
+                .and_then(|m| SystemTime::now().duration_since(m).ok());
+            if idle.is_some_and(|idle| idle > ttl) {
+                fs::remove_file(entry.path())?;
+                self.release(&owner, metadata.len());
+            }
+        }
+        Ok(())
+    }
+
+    /// Accounts `bytes` to `user` if it fits into the quotas and on the disk.
+    pub fn reserve(&self, user: &str, bytes: u64) -> Result<(), QuotaError> {
+        let available = self.available();
+        let mut usage = self.usage.lock().unwrap();
+        self.fits(&usage, available, user, bytes)?;
+        usage.total += bytes;
+        *usage.by_user.entry(user.to_string()).or_default() += bytes;
+        if let Some((_, free)) = self.free.lock().unwrap().as_mut() {
+            *free = free.saturating_sub(bytes);
+        }
+        Ok(())
+    }
+
+    /// Checks whether `bytes` could be reserved for `user` right now.
+    pub fn check(&self, user: &str, bytes: u64) -> Result<(), QuotaError> {
+        let available = self.available();
+        self.fits(&self.usage.lock().unwrap(), available, user, bytes)
+    }
+
+    /// Free disk space, measured at most once per [`FREE_SPACE_TTL`] so that chunks
+    /// don't each cost a filesystem query.
+    fn available(&self) -> u64 {
+        let mut free = self.free.lock().unwrap();
+        match *free {
+            Some((at, bytes)) if at.elapsed() < FREE_SPACE_TTL => bytes,
+            _ => {
+                let bytes = fs2::available_space(&self.dir).unwrap_or(0);
+                *free = Some((Instant::now(), bytes));
+                bytes
+            }
+        }
+    }
+
+    fn fits(
+        &self,
+        usage: &Usage,
+        available: u64,
+        user: &str,
+        bytes: u64,
+    ) -> Result<(), QuotaError> {
+        let limits = *self.limits.lock().unwrap();
+        let used = usage.by_user.get(user).copied().unwrap_or(0);
+        if limits.user_quota.is_some_and(|q| used + bytes > q) {
+            return Err(QuotaError::User);
+        }
+        if limits.global_quota.is_some_and(|q| usage.total + bytes > q) {
+            return Err(QuotaError::Global);
+        }
+        if available < bytes + limits.min_free_space {
+            return Err(QuotaError::Disk);
+        }
+        Ok(())
+    }
+
+    /// Bytes kept for `user`, by uploads in progress, partial uploads and offers.
+    pub fn used_by(&self, user: &str) -> u64 {
+        let usage = self.usage.lock().unwrap();
+        usage.by_user.get(user).copied().unwrap_or(0)
+    }
+
+    pub fn release(&self, user: &str, bytes: u64) {
+        let mut usage = self.usage.lock().unwrap();
+        usage.total = usage.total.saturating_sub(bytes);
+        if let Some(used) = usage.by_user.get_mut(user) {
+            *used = used.saturating_sub(bytes);
+            if *used == 0 {
+                usage.by_user.remove(user);
+            }
+        }
+    }
+
+    pub fn report(&self) -> UsageReport {
+        let usage = self.usage.lock().unwrap();
+        let limits = *self.limits.lock().unwrap();
+        let mut users: Vec<_> = usage
+            .by_user
+            .iter()
+            .map(|(user, bytes)| (user.clone(), *bytes))
+            .collect();
+        users.sort_by_key(|&(_, bytes)| std::cmp::Reverse(bytes));
+        UsageReport {
+            total: usage.total,
+            global_quota: limits.global_quota,
+            user_quota: limits.user_quota,
+            available: fs2::available_space(&self.dir).unwrap_or(0),
+            users,
+        }
+    }
+}
+
+/// The owner of a partial upload named by [`Storage::partial_path`].
+fn partial_owner(name: &str) -> Option<String> {
+    let owner = name.strip_suffix(".part")?.split_once('.')?.1;
+    if owner.len() % 2 != 0 || !owner.is_ascii() {
+        return None;
+    }
+    let bytes = (0..owner.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&owner[i..i + 2], 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// A file in the spool directory, removed together with its quota once the last reference is dropped.
+#[derive(Debug)]
+pub struct SpoolFile {
+    path: PathBuf,
+    owner: String,
+    size: u64,
+    storage: Arc<Storage>,
+}
+
+impl SpoolFile {
+    /// Takes ownership of `path` whose `size` bytes are already reserved for `owner`.
+    pub fn new(path: PathBuf, owner: &str, size: u64, storage: Arc<Storage>) -> Self {
+        Self {
+            path,
+            owner: owner.to_string(),
+            size,
+            storage,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+        self.storage.release(&self.owner, self.size);
+    }
+}
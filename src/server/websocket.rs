@@ -0,0 +1,83 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use tokio::{
+    io::{self, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::Sender,
+};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tracing::debug;
+
+use super::{handle_connection, read_piped_msg, Context, InternalMessage, BUF_SIZE};
+
+/// Accepts browser clients. Every binary frame carries messages in the same framing as
+/// the TCP protocol, and every message the server sends is a frame of its own.
+pub(super) async fn serve(
+    listener: TcpListener,
+    tx: Sender<InternalMessage>,
+    ctx: Arc<Context>,
+) -> io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let tx = tx.clone();
+        let ctx = Arc::clone(&ctx);
+        tokio::spawn(async move { handle(stream, addr, tx, ctx).await });
+    }
+}
+
+async fn handle(
+    stream: TcpStream,
+    addr: SocketAddr,
+    tx: Sender<InternalMessage>,
+    ctx: Arc<Context>,
+) -> io::Result<()> {
+    let ws = match tokio_tungstenite::accept_async(stream).await {
+        Ok(ws) => ws,
+        Err(e) => {
+            debug!(%addr, error = %e, "websocket handshake failed");
+            return Ok(());
+        }
+    };
+    let (sink, frames) = ws.split();
+    // the connection is handled like a TCP one, on the other end of a pipe
+    let (ours, theirs) = io::duplex(BUF_SIZE);
+    let (our_reader, our_writer) = io::split(ours);
+    tokio::spawn(frames_to_pipe(frames, our_writer));
+    tokio::spawn(pipe_to_frames(our_reader, sink));
+    let (reader, writer) = io::split(theirs);
+    handle_connection(reader, writer, addr, tx, ctx).await
+}
+
+async fn frames_to_pipe(
+    mut frames: SplitStream<WebSocketStream<TcpStream>>,
+    mut pipe: WriteHalf<DuplexStream>,
+) -> io::Result<()> {
+    while let Some(Ok(frame)) = frames.next().await {
+        match frame {
+            Message::Binary(data) => pipe.write_all(&data).await?,
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+    // the connection ends once the server reads to the end of the pipe
+    pipe.shutdown().await
+}
+
+async fn pipe_to_frames(
+    mut pipe: ReadHalf<DuplexStream>,
+    mut sink: SplitSink<WebSocketStream<TcpStream>, Message>,
+) -> io::Result<()> {
+    while let Ok(Some((desc, rest))) = read_piped_msg(&mut pipe).await {
+        let mut frame = desc.as_bytes().to_vec();
+        frame.extend_from_slice(&rest);
+        if sink.send(Message::Binary(frame)).await.is_err() {
+            return Ok(());
+        }
+    }
+    let _ = sink.send(Message::Close(None)).await;
+    Ok(())
+}